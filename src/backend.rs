@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use notify::{Watcher, RecommendedWatcher, PollWatcher, RecursiveMode, DebouncedEvent};
+
+use crate::global::prelude::*;
+use crate::roots::WatchRoot;
+
+/// How the file system is watched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    /// The native watcher, or polling if the native watcher fails to start.
+    Auto,
+    /// The native watcher of the platform, e.g. inotify.
+    Native,
+    /// Scans the roots periodically. Works on NFS, SSHFS and bind mounts, where the native watcher misses changes.
+    /// Compares modification times with a resolution of one second.
+    Poll,
+}
+
+impl BackendKind {
+
+    pub fn parse(value: &str) -> Result<BackendKind> {
+
+        match value {
+            "auto" => Ok(BackendKind::Auto),
+            "native" => Ok(BackendKind::Native),
+            "poll" => Ok(BackendKind::Poll),
+            _ => Err(CustomError::user_error(&format!("Unknown backend `{}`.", value))),
+        }
+    }
+}
+
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    /// How long the native watcher merges the raw events of a single file system operation.
+    pub delay: Duration,
+    /// How often the polling backend scans the roots.
+    pub poll_interval: Duration,
+}
+
+/// A native or a polling watcher. `notify::Watcher` can't be used as a trait object, so this wraps both.
+pub enum AnyWatcher {
+    Native(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+impl AnyWatcher {
+
+    pub fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result {
+
+        let result = match self {
+            AnyWatcher::Native(watcher) => watcher.watch(path, mode),
+            AnyWatcher::Poll(watcher) => watcher.watch(path, mode),
+        };
+
+        result.map_err(|error| CustomError::user_error(&format!(
+            "Failed to watch `{}`: {}",
+            path.to_string_lossy(),
+            describe_watcher_error(&error)
+        )))
+    }
+
+    pub fn unwatch(&mut self, path: &Path) -> Result {
+
+        match self {
+            AnyWatcher::Native(watcher) => watcher.unwatch(path)?,
+            AnyWatcher::Poll(watcher) => watcher.unwatch(path)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the watcher and starts watching the roots that exist.
+/// With `BackendKind::Auto`, falls back to polling if the native watcher fails to start or to watch a root.
+pub fn start_watcher(config: &BackendConfig, sender: Sender<DebouncedEvent>, roots: &[WatchRoot]) -> Result<AnyWatcher> {
+
+    match config.kind {
+        BackendKind::Native => start_native(config, sender, roots),
+        BackendKind::Poll => start_poll(config, sender, roots),
+        BackendKind::Auto => match start_native(config, sender.clone(), roots) {
+            Ok(watcher) => Ok(watcher),
+            Err(error) => {
+                elog!("The native watcher failed to start: {}", error.kind.to_string());
+
+                start_poll(config, sender, roots)
+            },
+        },
+    }
+}
+
+fn start_native(config: &BackendConfig, sender: Sender<DebouncedEvent>, roots: &[WatchRoot]) -> Result<AnyWatcher> {
+
+    let mut watcher = AnyWatcher::Native(Watcher::new(sender, config.delay)?);
+
+    watch_roots(&mut watcher, roots)?;
+
+    Ok(watcher)
+}
+
+fn start_poll(config: &BackendConfig, sender: Sender<DebouncedEvent>, roots: &[WatchRoot]) -> Result<AnyWatcher> {
+
+    log!("Polling for changes every {}.", humantime::format_duration(config.poll_interval));
+
+    let mut watcher = AnyWatcher::Poll(Watcher::new(sender, config.poll_interval)?);
+
+    watch_roots(&mut watcher, roots)?;
+
+    Ok(watcher)
+}
+
+fn watch_roots(watcher: &mut AnyWatcher, roots: &[WatchRoot]) -> Result {
+
+    for root in roots.iter().filter(|x| x.exists()) {
+        watcher.watch(&root.path, root.recursive_mode())?;
+    }
+
+    Ok(())
+}
+
+/// Describes a watcher error. Running out of inotify watches comes with an explanation of the limit.
+pub fn describe_watcher_error(error: &notify::Error) -> String {
+
+    match error {
+        notify::Error::Io(io_error) if io_error.raw_os_error() == Some(Errno::ENOSPC as i32) => format!(
+            "{}. The limit of inotify watches was reached, every watched directory uses one. \
+             Raise `fs.inotify.max_user_watches` (e.g. `sudo sysctl fs.inotify.max_user_watches=524288`), \
+             watch fewer directories with `--watch-flat`, or use `--poll`.",
+            io_error
+        ),
+        notify::Error::Io(io_error) => io_error.to_string(),
+        notify::Error::Generic(message) => message.clone(),
+        notify::Error::PathNotFound => "The path was not found.".to_string(),
+        notify::Error::WatchNotFound => "The watch was not found.".to_string(),
+    }
+}
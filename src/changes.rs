@@ -0,0 +1,132 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use notify::DebouncedEvent;
+use serde::Serialize;
+
+use crate::global::prelude::*;
+use crate::command_line::Placeholders;
+
+/// The kind of a file system change.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Create,
+    Write,
+    Remove,
+    Rename,
+    Chmod,
+}
+
+impl ChangeKind {
+
+    pub fn parse(value: &str) -> Result<ChangeKind> {
+
+        match value {
+            "create" => Ok(ChangeKind::Create),
+            "write" => Ok(ChangeKind::Write),
+            "remove" => Ok(ChangeKind::Remove),
+            "rename" => Ok(ChangeKind::Rename),
+            "chmod" => Ok(ChangeKind::Chmod),
+            _ => Err(CustomError::user_error(&format!("Unknown event kind `{}`.", value))),
+        }
+    }
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ChangeKind::Create => "create",
+            ChangeKind::Write => "write",
+            ChangeKind::Remove => "remove",
+            ChangeKind::Rename => "rename",
+            ChangeKind::Chmod => "chmod",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// A change to a single path.
+#[derive(Serialize, Debug, Clone)]
+pub struct Change {
+    /// The base directory of the watch root the change came from. See `WatchRoot::base`.
+    pub root: PathBuf,
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+/// Adds `change` to `changes`. If the path already changed, only its kind is updated.
+pub fn add_change(changes: &mut Vec<Change>, change: Change) {
+
+    match changes.iter_mut().find(|x| x.path == change.path) {
+        Some(existing) => existing.kind = change.kind,
+        None => changes.push(change),
+    }
+}
+
+/// The changed paths separated by new lines, for `WATCH_RUN_CHANGED_FILES`.
+pub fn format_changed_files(changes: &[Change]) -> String {
+
+    changes.iter()
+        .map(|x| x.path.to_string_lossy().to_string())
+        .collect_vec()
+        .join("\n")
+}
+
+/// `{files}` is every changed path and `{file}` is the last changed path.
+pub fn change_placeholders(changes: &[Change]) -> Placeholders {
+
+    let files = changes.iter()
+        .map(|x| x.path.to_string_lossy().to_string())
+        .collect_vec();
+
+    let file = files.last().cloned().into_iter().collect_vec();
+
+    vec![("files", files), ("file", file)]
+}
+
+pub trait DebounceEventExtensions {
+    fn get_path(&self) -> Option<PathBuf>;
+    fn get_change(&self, root: &Path) -> Option<Change>;
+    fn is_notice(&self) -> bool;
+}
+
+impl DebounceEventExtensions for DebouncedEvent {
+
+    fn get_path(&self) -> Option<PathBuf> {
+        match self {
+            DebouncedEvent::NoticeWrite(x) => Some(x.clone()),
+            DebouncedEvent::NoticeRemove(x) => Some(x.clone()),
+            DebouncedEvent::Create(x) => Some(x.clone()),
+            DebouncedEvent::Write(x) => Some(x.clone()),
+            DebouncedEvent::Chmod(x) => Some(x.clone()),
+            DebouncedEvent::Remove(x) => Some(x.clone()),
+            DebouncedEvent::Rename(_, x) => Some(x.clone()),
+            DebouncedEvent::Rescan => None,
+            DebouncedEvent::Error(_, x) => x.clone(),
+        }
+    }
+
+    fn get_change(&self, root: &Path) -> Option<Change> {
+
+        let kind = match self {
+            DebouncedEvent::NoticeWrite(_) => ChangeKind::Write,
+            DebouncedEvent::NoticeRemove(_) => ChangeKind::Remove,
+            DebouncedEvent::Create(_) => ChangeKind::Create,
+            DebouncedEvent::Write(_) => ChangeKind::Write,
+            DebouncedEvent::Chmod(_) => ChangeKind::Chmod,
+            DebouncedEvent::Remove(_) => ChangeKind::Remove,
+            DebouncedEvent::Rename(_, _) => ChangeKind::Rename,
+            DebouncedEvent::Rescan => return None,
+            DebouncedEvent::Error(_, _) => return None,
+        };
+
+        self.get_path().map(|path| Change { root: root.to_path_buf(), path, kind })
+    }
+
+    /// `NoticeWrite` and `NoticeRemove` are sent right away and followed by `Write`/`Remove` once the debounce delay passes.
+    fn is_notice(&self) -> bool {
+        matches!(self, DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_))
+    }
+}
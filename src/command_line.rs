@@ -0,0 +1,333 @@
+use std::ffi::OsString;
+
+use regex::{Captures, Regex};
+
+use crate::global::prelude::*;
+use crate::global::bash_shell::{self, RunningCommand, ShellConfig, SpawnOptions};
+
+lazy_static::lazy_static! {
+    static ref PLACEHOLDER_RE: Regex = Regex::new(r"\{(\w+)\}").unwrap();
+}
+
+/// The values of the `{name}` placeholders. A placeholder can have multiple values, like `{files}`.
+pub type Placeholders = Vec<(&'static str, Vec<String>)>;
+
+/// The command as given on the command line.
+#[derive(Clone, Debug)]
+pub enum CommandLine {
+    /// A single argument, passed to the shell as is. Can use pipes, `&&` and so on.
+    Script(String),
+    /// Multiple arguments. Each argument reaches the program exactly as typed, even if it is not valid UTF-8.
+    Args(Vec<OsString>),
+}
+
+impl CommandLine {
+
+    /// A single argument is a script, so it has to be valid UTF-8 for the placeholders to be found in it.
+    pub fn from_args(mut args: Vec<OsString>) -> Result<CommandLine> {
+
+        if args.len() == 1 {
+
+            let script = args.remove(0).into_string()
+                .map_err(|_| CustomError::user_error("A command in a single argument is a shell script and must be valid UTF-8."))?;
+
+            Ok(CommandLine::Script(script))
+        } else {
+            Ok(CommandLine::Args(args))
+        }
+    }
+
+    /// Returns the command as a shell script and the arguments that go with it.
+    /// In a script the placeholders become references to the positional parameters (`${1}`, `${2}`, ... in sh),
+    /// and the values are the arguments, so the shell never parses a value, like a file named `$(rm -rf ~)`.
+    /// An argument list is expanded like `render_args` does, and the script is a reference to each of the arguments.
+    pub fn render(&self, placeholders: &Placeholders, shell: &ShellConfig) -> (String, Vec<OsString>) {
+
+        match self {
+            CommandLine::Script(script) => {
+
+                let mut args = Vec::new();
+
+                let script = PLACEHOLDER_RE.replace_all(script, |captures: &Captures| {
+
+                    let values = match placeholders.iter().find(|(name, _)| *name == &captures[1]) {
+                        Some((_, values)) => values,
+                        None => return captures[0].to_string(),
+                    };
+
+                    let references = (args.len() + 1..=args.len() + values.len())
+                        .map(|x| shell.parameter(x))
+                        .collect_vec();
+
+                    args.extend(values.iter().map(OsString::from));
+
+                    // Every value is a separate word, unless the user quoted the placeholder.
+                    match quoting_at(script, captures.get(0).map(|x| x.start()).unwrap_or_default()) {
+                        Quoting::None => references.iter().map(|x| format!("\"{}\"", x)).collect_vec().join(" "),
+                        Quoting::Double => references.join(" "),
+                        Quoting::Single => format!("'\"{}\"'", references.join(" ")),
+                    }
+                }).to_string();
+
+                (script, args)
+            },
+            CommandLine::Args(args) => {
+
+                let args = self.expand_args(placeholders, args);
+
+                let script = (1..=args.len())
+                    .map(|x| format!("\"{}\"", shell.parameter(x)))
+                    .collect_vec()
+                    .join(" ");
+
+                (script, args)
+            },
+        }
+    }
+
+    /// Returns the arguments of the program with the placeholders replaced, for running it without a shell.
+    /// An argument that is exactly a placeholder becomes one argument per value,
+    /// and placeholders inside a longer argument are replaced with the values separated by spaces.
+    /// A script is split on whitespace, because there is no shell to parse it.
+    pub fn render_args(&self, placeholders: &Placeholders) -> Vec<OsString> {
+
+        match self {
+            CommandLine::Script(script) => {
+
+                let args = script.split_whitespace().map(OsString::from).collect_vec();
+
+                self.expand_args(placeholders, &args)
+            },
+            CommandLine::Args(args) => self.expand_args(placeholders, args),
+        }
+    }
+
+    /// Starts the command through the shell, or directly when `shell` is `None`.
+    pub fn spawn(&self, placeholders: &Placeholders, shell: &Option<ShellConfig>, options: SpawnOptions) -> Result<RunningCommand> {
+
+        match shell {
+            Some(shell) => {
+                let (script, args) = self.render(placeholders, shell);
+
+                bash_shell::spawn(&script, &args, shell, options)
+            },
+            None => bash_shell::spawn_args(&self.render_args(placeholders), options),
+        }
+    }
+
+    fn expand_args(&self, placeholders: &Placeholders, args: &[OsString]) -> Vec<OsString> {
+
+        let mut result = Vec::new();
+
+        for arg in args {
+
+            // The placeholders are looked for in text only. Other bytes are passed as they are.
+            let arg = match arg.to_str() {
+                Some(x) => x,
+                None => {
+                    result.push(arg.clone());
+
+                    continue;
+                },
+            };
+
+            match placeholders.iter().find(|(name, _)| arg == format!("{{{}}}", name)) {
+                Some((_, values)) => result.extend(values.iter().map(OsString::from)),
+                None => result.push(replace_placeholders(arg, placeholders, |values| values.join(" ")).into()),
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quoting {
+    None,
+    Single,
+    Double,
+}
+
+/// Returns the kind of shell quotes that are open at `position` in the script.
+fn quoting_at(script: &str, position: usize) -> Quoting {
+
+    let mut quoting = Quoting::None;
+    let mut escaped = false;
+
+    for c in script[..position].chars() {
+
+        if escaped {
+            escaped = false;
+
+            continue;
+        }
+
+        match (quoting, c) {
+            (Quoting::Single, '\'') => quoting = Quoting::None,
+            (Quoting::Single, _) => (),
+            (_, '\\') => escaped = true,
+            (Quoting::None, '\'') => quoting = Quoting::Single,
+            (Quoting::None, '"') => quoting = Quoting::Double,
+            (Quoting::Double, '"') => quoting = Quoting::None,
+            _ => (),
+        }
+    }
+
+    quoting
+}
+
+/// Replaces the known `{name}` placeholders in a single pass, so a value that contains a placeholder,
+/// like a file named `{file}`, is never replaced again. Unknown placeholders are left as they are.
+fn replace_placeholders<F>(text: &str, placeholders: &Placeholders, format_values: F) -> String
+    where F: Fn(&[String]) -> String {
+
+    PLACEHOLDER_RE.replace_all(text, |captures: &Captures| {
+
+        match placeholders.iter().find(|(name, _)| *name == &captures[1]) {
+            Some((_, values)) => format_values(values),
+            None => captures[0].to_string(),
+        }
+    }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::*;
+
+    fn placeholders(files: &[&str]) -> Placeholders {
+
+        let files = files.iter().map(|x| x.to_string()).collect_vec();
+        let file = files.last().cloned().into_iter().collect_vec();
+
+        vec![("files", files), ("file", file)]
+    }
+
+    /// Runs the script like `bash_shell::spawn` does and returns its stdout.
+    fn run_script(command: &CommandLine, placeholders: &Placeholders) -> Vec<u8> {
+
+        let (script, args) = command.render(placeholders, &ShellConfig::default());
+
+        let output = ::std::process::Command::new("bash")
+            .arg("-c")
+            .arg(script)
+            .arg("watch-run")
+            .args(args)
+            .current_dir(::std::env::temp_dir())
+            .output()
+            .unwrap();
+
+        output.stdout
+    }
+
+    fn os_strings(values: &[&str]) -> Vec<OsString> {
+
+        values.iter().map(OsString::from).collect_vec()
+    }
+
+    #[test]
+    fn render_passes_the_values_of_a_script_as_arguments() {
+
+        let command = CommandLine::Script("cat {files} > out".to_string());
+
+        assert_eq!(
+            command.render(&placeholders(&["a b", "it's"]), &ShellConfig::default()),
+            ("cat \"${1}\" \"${2}\" > out".to_string(), os_strings(&["a b", "it's"]))
+        );
+    }
+
+    #[test]
+    fn render_does_not_quote_the_references_inside_quotes() {
+
+        let command = CommandLine::Script("echo \"changed: {file}\" 'all: {files}' \\'{file}".to_string());
+
+        assert_eq!(
+            command.render(&placeholders(&["a", "b"]), &ShellConfig::default()).0,
+            "echo \"changed: ${1}\" 'all: '\"${2} ${3}\"'' \\'\"${4}\""
+        );
+    }
+
+    #[test]
+    fn render_does_not_replace_placeholders_inside_values() {
+
+        let command = CommandLine::Script("echo {files}".to_string());
+
+        assert_eq!(run_script(&command, &placeholders(&["{file};echo PWNED;"])), b"{file};echo PWNED;\n");
+    }
+
+    #[test]
+    fn render_does_not_let_the_shell_parse_the_values() {
+
+        let file = "$(echo PWNED) `echo PWNED` \"'; echo PWNED; '\" *";
+
+        for script in &["echo {file}", "echo \"changed: {file}\"", "echo 'changed: {file}'"] {
+
+            let command = CommandLine::Script(script.to_string());
+
+            let output = String::from_utf8(run_script(&command, &placeholders(&[file]))).unwrap();
+
+            assert!(!output.contains("PWNED\n") && output.ends_with(&format!("{}\n", file)), "{} printed {}", script, output);
+        }
+    }
+
+    #[test]
+    fn render_uses_argv_in_fish() {
+
+        let command = CommandLine::Script("echo {files}".to_string());
+
+        assert_eq!(
+            command.render(&placeholders(&["a", "b"]), &ShellConfig::new("fish", None)).0,
+            "echo \"$argv[2]\" \"$argv[3]\""
+        );
+    }
+
+    #[test]
+    fn render_keeps_unknown_placeholders() {
+
+        let command = CommandLine::Script("echo {other} {file}".to_string());
+
+        assert_eq!(command.render(&placeholders(&["a"]), &ShellConfig::default()).0, "echo {other} \"${1}\"");
+    }
+
+    #[test]
+    fn render_passes_the_arguments_as_parameters() {
+
+        let command = CommandLine::Args(os_strings(&["echo", "{files}", "x={file}"]));
+
+        assert_eq!(
+            command.render(&placeholders(&["a'b", "{files}"]), &ShellConfig::default()),
+            ("\"${1}\" \"${2}\" \"${3}\" \"${4}\"".to_string(), os_strings(&["echo", "a'b", "{files}", "x={files}"]))
+        );
+    }
+
+    #[test]
+    fn render_keeps_arguments_that_are_not_utf8() {
+
+        let arg = OsString::from_vec(b"caf\xe9 {file}".to_vec());
+
+        let command = CommandLine::Args(vec![OsString::from("printf"), OsString::from("%s"), arg]);
+
+        assert_eq!(run_script(&command, &placeholders(&["a"])), b"caf\xe9 {file}");
+    }
+
+    #[test]
+    fn expand_args_does_not_replace_placeholders_inside_values() {
+
+        let command = CommandLine::Args(os_strings(&["{files}", "--input={file}"]));
+
+        assert_eq!(
+            command.render_args(&placeholders(&["{file}", "b {files}"])),
+            os_strings(&["{file}", "b {files}", "--input=b {files}"])
+        );
+    }
+
+    #[test]
+    fn expand_args_keeps_braces_that_are_not_placeholders() {
+
+        let command = CommandLine::Args(os_strings(&["{}", "{a-b}", "{file}"]));
+
+        assert_eq!(command.render_args(&placeholders(&["x"])), os_strings(&["{}", "{a-b}", "x"]));
+    }
+}
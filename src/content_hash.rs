@@ -0,0 +1,147 @@
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::global::prelude::*;
+use crate::changes::{Change, ChangeKind};
+
+pub struct ContentHashConfig {
+    /// Files larger than this are not hashed and every write to them counts as a change.
+    pub max_file_size: u64,
+    /// The maximum number of hashes kept. The oldest ones are dropped first.
+    pub max_entries: usize,
+}
+
+/// Remembers the content hash of the files that changed, to drop writes that did not change the content.
+pub struct ContentHashCache {
+    config: ContentHashConfig,
+    hashes: HashMap<PathBuf, u64>,
+    /// The paths in `hashes` in the order they were added.
+    order: VecDeque<PathBuf>,
+}
+
+impl ContentHashCache {
+
+    pub fn new(config: ContentHashConfig) -> ContentHashCache {
+        ContentHashCache {
+            config,
+            hashes: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Hashes the files that are not in the cache yet, so that the first write to them is compared too.
+    /// Stops once the cache is full.
+    pub fn seed<'a, I>(&mut self, paths: I) -> Result
+        where I: IntoIterator<Item = &'a Path> {
+
+        for path in paths {
+
+            if self.order.len() >= self.config.max_entries {
+                break;
+            }
+
+            if self.hashes.contains_key(path) {
+                continue;
+            }
+
+            if let Some(hash) = self.hash_file(path)? {
+                self.remember(path, hash);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `false` if the change is a create/write that left the file with the same content as the last time it was seen.
+    /// A change to a file that is not in the cache always counts, because there is nothing to compare it with.
+    pub fn is_changed(&mut self, change: &Change) -> Result<bool> {
+
+        match change.kind {
+            ChangeKind::Create | ChangeKind::Write => (),
+            ChangeKind::Remove => {
+                self.forget(&change.path);
+
+                return Ok(true);
+            },
+            ChangeKind::Rename | ChangeKind::Chmod => return Ok(true),
+        }
+
+        let hash = match self.hash_file(&change.path)? {
+            Some(x) => x,
+            None => {
+                self.forget(&change.path);
+
+                return Ok(true);
+            },
+        };
+
+        match self.remember(&change.path, hash) {
+            Some(previous_hash) => Ok(previous_hash != hash),
+            None => Ok(true),
+        }
+    }
+
+    /// Stores the hash and returns the previous one. Drops the oldest hashes if the cache is over the limit.
+    fn remember(&mut self, path: &Path, hash: u64) -> Option<u64> {
+
+        let previous_hash = self.hashes.insert(path.to_path_buf(), hash);
+
+        if previous_hash.is_none() {
+
+            self.order.push_back(path.to_path_buf());
+
+            while self.order.len() > self.config.max_entries {
+
+                if let Some(oldest) = self.order.pop_front() {
+                    self.hashes.remove(&oldest);
+                }
+            }
+        }
+
+        previous_hash
+    }
+
+    fn forget(&mut self, path: &Path) {
+
+        if self.hashes.remove(path).is_some() {
+            self.order.retain(|x| x != path);
+        }
+    }
+
+    /// Returns `None` for directories, missing files and files over the size limit.
+    fn hash_file(&self, path: &Path) -> Result<Option<u64>> {
+
+        let metadata = match ::std::fs::metadata(path) {
+            Ok(x) => x,
+            Err(_) => return Ok(None),
+        };
+
+        if !metadata.is_file() || metadata.len() > self.config.max_file_size {
+            return Ok(None);
+        }
+
+        let mut file = match File::open(path) {
+            Ok(x) => x,
+            Err(_) => return Ok(None),
+        };
+
+        let mut hasher = DefaultHasher::new();
+        let mut buffer = [0; 64 * 1024];
+
+        loop {
+            let count = file.read(&mut buffer)?;
+
+            if count == 0 {
+                break;
+            }
+
+            hasher.write(&buffer[..count]);
+        }
+
+        Ok(Some(hasher.finish()))
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use crate::global::prelude::*;
+use crate::runner::RunEvent;
+
+/// When watch-run exits on its own. Set with `--once`, `--exit-after` and `--idle-timeout`.
+pub struct ExitPolicyConfig {
+    /// The number of finished runs after which watch-run exits.
+    pub exit_after: Option<u64>,
+    /// How long nothing has to change before watch-run exits. Not counted while a run is active.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Tracks the runs and changes, and returns the exit code once watch-run should exit.
+pub struct ExitPolicy {
+    config: ExitPolicyConfig,
+    finished_runs: u64,
+    active_runs: u64,
+    /// The exit code of the last finished run. 0 before the first run.
+    last_exit_code: i32,
+    /// When the last change was accepted or the last run finished.
+    last_activity: Instant,
+}
+
+impl ExitPolicy {
+
+    pub fn new(config: ExitPolicyConfig) -> ExitPolicy {
+        ExitPolicy {
+            config,
+            finished_runs: 0,
+            active_runs: 0,
+            last_exit_code: 0,
+            last_activity: Instant::now(),
+        }
+    }
+
+    pub fn on_change(&mut self) {
+
+        self.last_activity = Instant::now();
+    }
+
+    /// Returns the exit code if the run limit was reached.
+    pub fn on_run(&mut self, event: RunEvent) -> Result<Option<i32>> {
+
+        match event {
+            RunEvent::Started => {
+                self.active_runs += 1;
+
+                Ok(None)
+            },
+            RunEvent::Finished(exit_code) => {
+                self.active_runs = self.active_runs.saturating_sub(1);
+                self.finished_runs += 1;
+                self.last_exit_code = exit_code;
+                self.last_activity = Instant::now();
+
+                match self.config.exit_after {
+                    Some(exit_after) if self.finished_runs >= exit_after => {
+                        log!("Exiting after {} run(s) ...", self.finished_runs);
+
+                        Ok(Some(exit_code))
+                    },
+                    _ => Ok(None),
+                }
+            },
+        }
+    }
+
+    /// Returns the exit code if nothing happened for the idle timeout.
+    pub fn check_idle(&self) -> Result<Option<i32>> {
+
+        let idle_timeout = match self.config.idle_timeout {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if self.active_runs > 0 || self.last_activity.elapsed() < idle_timeout {
+            return Ok(None);
+        }
+
+        log!("Nothing changed for {}. Exiting ...", humantime::format_duration(idle_timeout));
+
+        Ok(Some(self.last_exit_code))
+    }
+}
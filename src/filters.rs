@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::global::prelude::*;
+use crate::ignore_rules::IgnoreRules;
+use crate::changes::ChangeKind;
+
+/// Decides which kinds of events count as a change.
+pub struct EventKindFilter {
+    kinds: Vec<ChangeKind>,
+    /// Whether `NoticeWrite`/`NoticeRemove` count as a change.
+    notices: bool,
+}
+
+impl EventKindFilter {
+
+    /// Parses a comma separated list like `create,write,remove,rename`.
+    /// `notice` enables the `NoticeWrite`/`NoticeRemove` events.
+    pub fn parse(value: &str) -> Result<EventKindFilter> {
+
+        let mut kinds = Vec::new();
+        let mut notices = false;
+
+        for name in value.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+
+            if name == "notice" {
+                notices = true;
+            } else {
+                kinds.push(ChangeKind::parse(name)?);
+            }
+        }
+
+        Ok(EventKindFilter {
+            kinds,
+            notices,
+        })
+    }
+
+    pub fn is_match(&self, kind: ChangeKind, is_notice: bool) -> bool {
+
+        if is_notice && !self.notices {
+            return false;
+        }
+
+        self.kinds.contains(&kind)
+    }
+}
+
+/// Decides whether a changed path should trigger a run.
+#[derive(Clone)]
+pub struct PathFilter {
+    root: PathBuf,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    ignore_rules: Option<IgnoreRules>,
+}
+
+impl PathFilter {
+
+    /// Creates a filter for paths under `root` from the `--include` and `--exclude` patterns.
+    /// If `use_ignore_files` is set, paths ignored by `.gitignore`/`.ignore` files are also dropped.
+    pub fn new(root: &Path, include: &[String], exclude: &[String], use_ignore_files: bool) -> Result<PathFilter> {
+
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(include)?)
+        };
+
+        Ok(PathFilter {
+            root: root.to_path_buf(),
+            include,
+            exclude: build_glob_set(exclude)?,
+            ignore_rules: if use_ignore_files { Some(IgnoreRules::load(root)?) } else { None },
+        })
+    }
+
+    /// Reloads the ignore rules if `path` is an ignore file. Returns `true` if they were reloaded.
+    pub fn handle_change(&mut self, path: &Path) -> Result<bool> {
+
+        if let Some(ignore_rules) = &mut self.ignore_rules {
+
+            if ignore_rules.is_ignore_file(path) {
+
+                log!("Reloading the ignore rules because `{}` changed.", path.get_as_string()?);
+
+                ignore_rules.reload()?;
+
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The ignore files that are not under the root, or are not found by walking it.
+    pub fn extra_ignore_files(&self) -> &[PathBuf] {
+
+        match &self.ignore_rules {
+            Some(ignore_rules) => ignore_rules.extra_files(),
+            None => &[],
+        }
+    }
+
+    /// Returns `true` if a change to `path` should trigger a run.
+    /// The patterns are matched against the path relative to the watch root.
+    /// A path is excluded if the path itself or any of its parent directories matches an exclude pattern,
+    /// so `--exclude target` also drops everything under `target/`.
+    pub fn is_match(&self, path: &Path) -> bool {
+
+        if self.is_excluded(path) {
+            return false;
+        }
+
+        match &self.include {
+            Some(include) => include.is_match(path.strip_prefix(&self.root).unwrap_or(path)),
+            None => true,
+        }
+    }
+
+    /// Returns `true` if `path` is dropped by the exclude patterns or the ignore rules.
+    /// The include patterns are not checked, so everything under an excluded directory can be skipped.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+
+        let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
+
+        if relative_path.ancestors().any(|x| !x.as_os_str().is_empty() && self.exclude.is_match(x)) {
+            return true;
+        }
+
+        match &self.ignore_rules {
+            Some(ignore_rules) => ignore_rules.is_ignored(path),
+            None => false,
+        }
+    }
+}
+
+/// Returns the error of the first pattern that is not a valid glob.
+pub fn check_globs(patterns: &[String]) -> Result {
+
+    build_glob_set(patterns).map(|_| ())
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+
+    Ok(builder.build()?)
+}
@@ -1,19 +1,169 @@
-use std::process::{Command, Stdio};
+use std::process::{Command, Stdio, ChildStdin, ExitStatus};
+use std::os::unix::process::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
 use std::thread::JoinHandle;
 use std::thread;
-use std::io::{BufReader, Write, BufRead};
+use std::io::{BufReader, BufRead, ErrorKind, Read, Write};
+use std::fs::File;
+use std::path::Path;
+use std::ffi::OsString;
+
+use nix::errno::Errno;
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::{isatty, Pid};
 
 use super::prelude::*;
+use super::errors::CustomErrorKind;
 use crate::global::logger;
 
+/// Called from a background thread when a spawned command exits.
+pub type ExitCallback = Box<dyn FnOnce() + Send>;
+
+/// A file that receives the output of commands. Shared by the reader threads, so the lines of stdout and stderr stay whole.
+pub type OutputFile = Arc<Mutex<File>>;
+
+lazy_static::lazy_static! {
+    /// The PIDs of the commands that are running, and whether they have their own process group.
+    static ref ACTIVE_COMMANDS: Mutex<Vec<(u32, bool)>> = Mutex::new(Vec::new());
+}
+
+/// Longer lines of output are split, so a command that never prints a line break can't fill the memory.
+static MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// How much of the end of stdout and stderr is kept in `CommandResult`. The rest is only logged.
+static OUTPUT_TAIL_LENGTH: usize = 64 * 1024;
+
+/// The shell that runs the commands.
+#[derive(Clone, Debug)]
+pub struct ShellConfig {
+    /// The name of the shell in `PATH` or the path to it.
+    pub program: String,
+    /// Runs before every command, e.g. `set -exu`. Can be empty.
+    pub preamble: String,
+}
+
+impl ShellConfig {
+
+    /// Uses `set -exu` as the preamble for the POSIX-like shells and no preamble for the rest, like fish.
+    pub fn new(program: &str, preamble: Option<&str>) -> ShellConfig {
+
+        let default_preamble = match Path::new(program).file_name().and_then(|x| x.to_str()) {
+            Some("bash") | Some("sh") | Some("dash") | Some("zsh") | Some("ksh") => "set -exu",
+            _ => "",
+        };
+
+        ShellConfig {
+            program: program.to_string(),
+            preamble: preamble.unwrap_or(default_preamble).to_string(),
+        }
+    }
+
+    /// How a script refers to the positional parameter `index` that `spawn` passes to it, starting from 1.
+    /// fish has no `$0`, so the name of the script is `$argv[1]` there and the parameters come after it.
+    pub fn parameter(&self, index: usize) -> String {
+
+        match Path::new(&self.program).file_name().and_then(|x| x.to_str()) {
+            Some("fish") => format!("$argv[{}]", index + 1),
+            _ => format!("${{{}}}", index),
+        }
+    }
+}
+
+impl Default for ShellConfig {
+    fn default() -> ShellConfig {
+        ShellConfig::new("bash", None)
+    }
+}
+
+#[derive(Default)]
+pub struct SpawnOptions {
+    /// Additional environment variables for the command.
+    pub env: Vec<(String, String)>,
+    /// Passes the stdin of watch-run to the command. Otherwise the command gets a pipe that stays open until it exits.
+    pub forward_stdin: bool,
+    pub timeout: Option<Timeout>,
+    pub on_exit: Option<ExitCallback>,
+    /// Also writes the lines of stdout and stderr to this file.
+    pub output_file: Option<OutputFile>,
+}
+
+/// Stops a command that runs for too long.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeout {
+    pub duration: Duration,
+    /// How long to wait after SIGTERM before sending SIGKILL.
+    pub grace: Duration,
+}
+
+#[allow(unused)]
 pub fn exec(command: &str) -> Result<CommandResult> {
 
-    let mut process = Command::new("/usr/bin/env")
-        .arg("bash")
+    exec_with_options(command, &ShellConfig::default(), SpawnOptions::default())
+}
+
+pub fn exec_with_options(command: &str, shell: &ShellConfig, options: SpawnOptions) -> Result<CommandResult> {
+
+    spawn(command, &[], shell, options)?.wait()
+}
+
+/// Starts the command with `<shell> -c` in its own process group (see `start`) and returns without waiting for it to exit.
+/// `args` are the positional parameters of the script, `$1` and so on.
+pub fn spawn(command: &str, args: &[OsString], shell: &ShellConfig, options: SpawnOptions) -> Result<RunningCommand> {
+
+    let script = if shell.preamble.is_empty() {
+        command.to_string()
+    } else {
+        format!("{}\n{}", shell.preamble, command)
+    };
+
+    let mut process = Command::new("/usr/bin/env");
+    process.arg(&shell.program).arg("-c").arg(script).arg("watch-run").args(args);
+
+    start(process, command.to_string(), options)
+}
+
+/// Starts the program directly, without a shell. `args[0]` is the name of the program in `PATH` or the path to it.
+pub fn spawn_args(args: &[OsString], options: SpawnOptions) -> Result<RunningCommand> {
+
+    let (program, program_args) = args.split_first()
+        .ok_or_else(|| CustomError::user_error("The command is empty."))?;
+
+    let mut process = Command::new(program);
+    process.args(program_args);
+
+    let command = args.iter().map(|x| quote(&x.to_string_lossy())).collect_vec().join(" ");
+
+    start(process, command, options).map_err(|error| match &error.kind {
+        CustomErrorKind::IoError(io_error) if io_error.kind() == ErrorKind::NotFound =>
+            CustomError::user_error(&format!("The program `{}` was not found.", program.to_string_lossy())),
+        _ => error,
+    })
+}
+
+fn start(mut process: Command, command: String, options: SpawnOptions) -> Result<RunningCommand> {
+
+    let on_exit = options.on_exit;
+
+    // A command in another process group can't read from the terminal (it gets SIGTTIN and stops),
+    // so a command that gets the terminal as stdin stays in the foreground group of watch-run.
+    let own_group = !(options.forward_stdin && isatty(::std::io::stdin().as_raw_fd()).unwrap_or(false));
+
+    process
+        .envs(options.env)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .stdin(Stdio::piped())
-        .spawn()?;
+        .stdin(if options.forward_stdin { Stdio::inherit() } else { Stdio::piped() });
+
+    if own_group {
+        process.process_group(0);
+    }
+
+    let mut process = process.spawn()?;
 
     let stdout = process.stdout.take()
         .ok_or_else(|| CustomError::from_message("stdout was not redirected."))?;
@@ -21,65 +171,394 @@ pub fn exec(command: &str) -> Result<CommandResult> {
     let stderr = process.stderr.take()
         .ok_or_else(|| CustomError::from_message("stderr was not redirected."))?;
 
-    let stdin = process.stdin.as_mut()
-        .ok_or_else(|| CustomError::from_message("stdin was not redirected."))?;
+    let stdin = process.stdin.take();
 
-    let stdout_thread : JoinHandle<Result<String>> = thread::spawn(|| {
+    // Every thread of the command holds a sender. The receiver is disconnected once
+    // the process has exited and both output pipes are closed.
+    let (done_sender, done_receiver) = channel::<()>();
 
-        let buff = BufReader::new(stdout);
+    let stdout_done = done_sender.clone();
+    let stdout_file = options.output_file.clone();
 
-        let mut result = String::new();
+    let stdout_thread : JoinHandle<Result<String>> = thread::spawn(move || {
 
-        for line_result in buff.lines() {
+        let _done = stdout_done;
 
-            let line = line_result?;
-            result.push_str(&format!("{}\n", line));
-            logger().log(&format!("OUT | {}", line))?;
-        }
+        read_output(stdout, "OUT", stdout_file)
+    });
+
+    let stderr_done = done_sender.clone();
+    let stderr_file = options.output_file;
 
-        Ok(result)
+    let stderr_thread : JoinHandle<Result<String>> = thread::spawn(move || {
+
+        let _done = stderr_done;
+
+        read_output(stderr, "ERR", stderr_file)
     });
 
-    let stderr_thread : JoinHandle<Result<String>> = thread::spawn(|| {
+    let pid = process.id();
 
-        let buff = BufReader::new(stderr);
+    active_commands().push((pid, own_group));
 
-        let mut result = String::new();
+    let (exit_sender, exit_receiver) = channel();
 
-        for line_result in buff.lines() {
+    thread::spawn(move || {
 
-            let line = line_result?;
-            result.push_str(&format!("{}\n", line));
-            logger().log(&format!("ERR | {}", line))?;
-        }
+        let _ = exit_sender.send(process.wait());
 
-        Ok(result)
+        drop(done_sender);
     });
 
-    stdin.write_all("set -exu\n".as_bytes())?;
-    stdin.write_all(format!("{}\n", command).as_bytes())?;
-    stdin.write_all("exit $?;\n".as_bytes())?;
+    // A process that the command started can keep the output pipes open after the command exited,
+    // so the command only counts as finished once the pipes are closed too.
+    let finished = Arc::new(AtomicBool::new(false));
 
-    let out_result = stdout_thread.join().replace_error(||
-            CustomError::from_message("The stdout thread failed for some reason."))??;
+    // Disconnected once the command is finished.
+    let (finished_sender, finished_receiver) = channel::<()>();
+
+    let thread_finished = finished.clone();
+
+    thread::spawn(move || {
+
+        let _finished = finished_sender;
 
-    let err_result = stderr_thread.join().replace_error(||
-        CustomError::from_message("The stderr thread failed for some reason."))??;
+        let _ = done_receiver.recv();
 
-    let exit_status = process.wait()?;
+        thread_finished.store(true, Ordering::SeqCst);
 
-    return Ok(CommandResult {
-        status_code: exit_status.code(),
-        success: exit_status.success(),
-        stdout: out_result,
-        stderr: err_result,
-        command: command.to_string()
+        active_commands().retain(|(x, _)| *x != pid);
+
+        if let Some(on_exit) = on_exit {
+            on_exit();
+        }
     });
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+
+    if let Some(timeout) = options.timeout {
+
+        let timed_out = timed_out.clone();
+
+        thread::spawn(move || enforce_timeout(pid, own_group, timeout, finished_receiver, timed_out));
+    }
+
+    Ok(RunningCommand {
+        pid,
+        own_group,
+        finished,
+        timed_out,
+        stdin,
+        exit_receiver,
+        exit_status: None,
+        stdout_thread,
+        stderr_thread,
+        command,
+    })
+}
+
+/// Logs the lines of a stdout or stderr pipe with `label` and writes them to `output_file` until the pipe is closed.
+/// Output that is not valid UTF-8 is logged with replacement characters and written to the file as is.
+fn read_output<R: Read>(stream: R, label: &str, mut output_file: Option<OutputFile>) -> Result<String> {
+
+    let mut result = String::new();
+
+    read_lines(stream, |line| {
+
+        let text = String::from_utf8_lossy(line);
+
+        result.push_str(&text);
+        result.push('\n');
+
+        // Trimmed only once the text is twice as long as the tail, so that not every line moves the whole text.
+        if result.len() > 2 * OUTPUT_TAIL_LENGTH {
+            keep_tail(&mut result, OUTPUT_TAIL_LENGTH);
+        }
+
+        logger().log(&format!("{} | {}", label, text))?;
+
+        let write_result = match &output_file {
+            Some(output_file) => {
+                let mut output_file = output_file.lock()?;
+
+                output_file.write_all(line).and_then(|_| output_file.write_all(b"\n"))
+            },
+            None => Ok(()),
+        };
+
+        // The output still has to be read, or the command blocks once the pipe is full.
+        if let Err(error) = write_result {
+
+            logger().elog(&format!("Failed to save the output: {}. The rest of the {} output is not saved.", error, label))?;
+
+            output_file = None;
+        }
+
+        Ok(())
+    })?;
+
+    keep_tail(&mut result, OUTPUT_TAIL_LENGTH);
+
+    Ok(result)
+}
+
+/// Removes the start of the text, so that at most `length` bytes are left.
+fn keep_tail(text: &mut String, length: usize) {
+
+    if text.len() <= length {
+        return;
+    }
+
+    let mut start = text.len() - length;
+
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+
+    text.drain(..start);
+}
+
+/// Passes every line of `reader` to `on_line` without the line break, as bytes.
+/// The last line is passed on even if it does not end with a line break,
+/// and lines longer than `MAX_LINE_LENGTH` are passed on in parts.
+fn read_lines<R, F>(reader: R, mut on_line: F) -> Result
+    where R: Read, F: FnMut(&[u8]) -> Result {
+
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    // The last line was passed on because it reached `MAX_LINE_LENGTH`.
+    let mut was_split = false;
+
+    loop {
+        let (consumed, is_complete) = {
+
+            let buffer = match reader.fill_buf() {
+                Ok(x) => x,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            };
+
+            if buffer.is_empty() {
+                break;
+            }
+
+            let chunk = &buffer[..buffer.len().min(MAX_LINE_LENGTH - line.len())];
+
+            match chunk.iter().position(|x| *x == b'\n') {
+                // The line break of a line that is exactly `MAX_LINE_LENGTH` long.
+                Some(0) if was_split => (1, false),
+                Some(index) => {
+                    line.extend_from_slice(&chunk[..index]);
+
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+
+                    (index + 1, true)
+                },
+                None => {
+                    line.extend_from_slice(chunk);
+
+                    (chunk.len(), line.len() >= MAX_LINE_LENGTH)
+                },
+            }
+        };
+
+        reader.consume(consumed);
+
+        was_split = is_complete && line.len() >= MAX_LINE_LENGTH;
+
+        if is_complete {
+
+            on_line(&line)?;
+
+            line.clear();
+        }
+    }
+
+    if !line.is_empty() {
+        on_line(&line)?;
+    }
+
+    Ok(())
+}
+
+/// A command that was started with `spawn` and may still be running.
+pub struct RunningCommand {
+    pid: u32,
+    /// `false` when the command reads from the terminal and shares the process group of watch-run.
+    own_group: bool,
+    /// Kept open while the command runs, so programs that read stdin wait instead of getting EOF.
+    /// `None` when the stdin of watch-run is forwarded.
+    #[allow(unused)]
+    stdin: Option<ChildStdin>,
+    /// Set once the process has exited and its output was read to the end.
+    finished: Arc<AtomicBool>,
+    exit_receiver: Receiver<::std::io::Result<ExitStatus>>,
+    exit_status: Option<ExitStatus>,
+    /// Set when the command was stopped because of `SpawnOptions::timeout`.
+    timed_out: Arc<AtomicBool>,
+    stdout_thread: JoinHandle<Result<String>>,
+    stderr_thread: JoinHandle<Result<String>>,
+    command: String,
+}
+
+impl RunningCommand {
+
+    /// The PID of the shell or of the program, which is also the ID of the command's process group, unless it reads from the terminal.
+    pub fn pid(&self) -> u32 {
+
+        self.pid
+    }
+
+    /// Returns `true` if the shell or the program has exited and its output was read to the end.
+    /// `wait` does not block after that.
+    pub fn has_exited(&mut self) -> Result<bool> {
+
+        if !self.finished.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+
+        // The wait thread sends the exit status before the command counts as finished.
+        if self.exit_status.is_none() {
+
+            match self.exit_receiver.try_recv() {
+                Ok(exit_status) => self.exit_status = Some(exit_status?),
+                Err(_) => return Err(CustomError::from_message("The wait thread failed for some reason.")),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Sends SIGTERM to the whole process group of the command.
+    pub fn terminate(&self) -> Result {
+
+        self.signal(Signal::SIGTERM)
+    }
+
+    /// Sends a signal to the whole process group of the command.
+    pub fn signal(&self, signal: Signal) -> Result {
+
+        signal_command(self.pid, self.own_group, signal)
+    }
+
+    /// Waits for the command to exit and for its output to be read.
+    pub fn wait(self) -> Result<CommandResult> {
+
+        let out_result = self.stdout_thread.join().replace_error(||
+            CustomError::from_message("The stdout thread failed for some reason."))??;
+
+        let err_result = self.stderr_thread.join().replace_error(||
+            CustomError::from_message("The stderr thread failed for some reason."))??;
+
+        let exit_status = match self.exit_status {
+            Some(exit_status) => exit_status,
+            None => self.exit_receiver.recv().replace_error(||
+                CustomError::from_message("The wait thread failed for some reason."))??,
+        };
+
+        let timed_out = self.timed_out.load(Ordering::SeqCst);
+
+        return Ok(CommandResult {
+            status_code: exit_status.code(),
+            signal: exit_status.signal(),
+            timed_out,
+            success: exit_status.success() && !timed_out,
+            stdout: out_result,
+            stderr: err_result,
+            command: self.command,
+        });
+    }
+}
+
+/// Sends a signal to the process group `pid`. A group that is already gone is not an error.
+pub fn signal_group(pid: u32, signal: Signal) -> Result {
+
+    match killpg(Pid::from_raw(pid as i32), signal) {
+        Ok(()) => Ok(()),
+        // The process group is already gone.
+        Err(nix::Error::Sys(Errno::ESRCH)) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Sends SIGKILL to every command that is still running, for when watch-run has to exit right away.
+pub fn kill_all() -> Result {
+
+    for (pid, own_group) in active_commands().iter() {
+        signal_command(*pid, *own_group, Signal::SIGKILL)?;
+    }
+
+    Ok(())
+}
+
+/// The list can't be left half updated, so a panic while it was locked does not matter.
+fn active_commands() -> MutexGuard<'static, Vec<(u32, bool)>> {
+
+    ACTIVE_COMMANDS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Sends a signal to the process group of the command, or only to the process if it shares the group of watch-run.
+fn signal_command(pid: u32, own_group: bool, signal: Signal) -> Result {
+
+    if own_group {
+        return signal_group(pid, signal);
+    }
+
+    match kill(Pid::from_raw(pid as i32), signal) {
+        Ok(()) => Ok(()),
+        // The process is already gone.
+        Err(nix::Error::Sys(Errno::ESRCH)) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Sends SIGTERM to the process group if the command is not done within the timeout, and SIGKILL if it is still not done after the grace period.
+/// Killing the whole group closes the output pipes, so the reader threads finish too.
+fn enforce_timeout(pid: u32, own_group: bool, timeout: Timeout, finished_receiver: Receiver<()>, timed_out: Arc<AtomicBool>) -> Result {
+
+    if let Err(RecvTimeoutError::Timeout) = finished_receiver.recv_timeout(timeout.duration) {
+
+        timed_out.store(true, Ordering::SeqCst);
+
+        logger().log(&format!(
+            "The command (PID {}) timed out after {}. Sending SIGTERM ...",
+            pid,
+            humantime::format_duration(timeout.duration)
+        ))?;
+
+        signal_command(pid, own_group, Signal::SIGTERM)?;
+
+        if let Err(RecvTimeoutError::Timeout) = finished_receiver.recv_timeout(timeout.grace) {
+
+            logger().log(&format!(
+                "The command (PID {}) is still running after {}. Sending SIGKILL ...",
+                pid,
+                humantime::format_duration(timeout.grace)
+            ))?;
+
+            signal_command(pid, own_group, Signal::SIGKILL)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Quotes a value so that bash treats it as a single word.
+pub fn quote(value: &str) -> String {
+
+    format!("'{}'", value.replace("'", "'\\''"))
 }
 
 #[derive(Debug)]
 pub struct CommandResult {
     pub status_code: Option<i32>,
+    /// The signal that killed the command, if any.
+    pub signal: Option<i32>,
+    /// The command was stopped because it ran longer than the timeout.
+    pub timed_out: bool,
+    /// The last `OUTPUT_TAIL_LENGTH` bytes of the output.
     pub stdout: String,
     pub stderr: String,
     pub command: String,
@@ -88,6 +567,35 @@ pub struct CommandResult {
 
 impl CommandResult {
 
+    /// Describes how the command exited, e.g. `exit code 1` or `a timeout (SIGTERM)`.
+    pub fn exit_description(&self) -> String {
+
+        let status = match (self.status_code, self.signal) {
+            (Some(code), _) => format!("exit code {}", code),
+            (None, Some(signal)) => match Signal::from_c_int(signal) {
+                Ok(signal) => signal.to_string(),
+                Err(_) => format!("signal {}", signal),
+            },
+            (None, None) => "an unknown status".to_string(),
+        };
+
+        if self.timed_out {
+            format!("a timeout ({})", status)
+        } else {
+            status
+        }
+    }
+
+    /// The exit code that a shell would report: the status code, or 128 + the signal number.
+    pub fn exit_code(&self) -> i32 {
+
+        match (self.status_code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+
     //noinspection RsSelfConvention
     #[allow(unused)]
     pub fn as_result(self) -> Result<CommandResult> {
@@ -110,4 +618,119 @@ macro_rules! bash_exec {
     ($($x:expr),*) => {
         crate::global::bash_shell::exec(&format!($($x,)*))?.as_result()?
     };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Returns the chunks one `read` at a time, like a pipe.
+    struct ChunkedReader {
+        chunks: VecDeque<Vec<u8>>,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buffer: &mut [u8]) -> ::std::io::Result<usize> {
+
+            let chunk = match self.chunks.pop_front() {
+                Some(x) => x,
+                None => return Ok(0),
+            };
+
+            let length = chunk.len().min(buffer.len());
+
+            buffer[..length].copy_from_slice(&chunk[..length]);
+
+            if length < chunk.len() {
+                self.chunks.push_front(chunk[length..].to_vec());
+            }
+
+            Ok(length)
+        }
+    }
+
+    fn lines(chunks: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+
+        let mut lines = Vec::new();
+
+        read_lines(ChunkedReader { chunks: chunks.into_iter().collect() }, |line| {
+            lines.push(line.to_vec());
+
+            Ok(())
+        }).unwrap();
+
+        lines
+    }
+
+    #[test]
+    fn read_lines_passes_on_a_partial_last_line() {
+        assert_eq!(lines(vec![b"first\nsec".to_vec(), b"ond".to_vec()]), vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn read_lines_keeps_empty_lines() {
+        assert_eq!(lines(vec![b"\n\nx\n".to_vec()]), vec![b"".to_vec(), b"".to_vec(), b"x".to_vec()]);
+    }
+
+    #[test]
+    fn read_lines_does_not_add_a_line_after_a_line_of_max_length() {
+
+        let line = vec![b'a'; MAX_LINE_LENGTH];
+
+        let mut text = line.clone();
+        text.extend_from_slice(b"\nb\n");
+
+        assert_eq!(lines(vec![text]), vec![line, b"b".to_vec()]);
+    }
+
+    #[test]
+    fn read_lines_splits_longer_lines() {
+
+        let mut text = vec![b'a'; MAX_LINE_LENGTH + 1];
+        text.push(b'\n');
+
+        assert_eq!(lines(vec![text]), vec![vec![b'a'; MAX_LINE_LENGTH], b"a".to_vec()]);
+    }
+
+    #[test]
+    fn read_lines_removes_a_carriage_return_split_from_its_line_feed() {
+        assert_eq!(lines(vec![b"one\r".to_vec(), b"\ntwo\r\n".to_vec()]), vec![b"one".to_vec(), b"two".to_vec()]);
+    }
+
+    #[test]
+    fn read_lines_keeps_a_carriage_return_inside_a_line() {
+        assert_eq!(lines(vec![b"50%\r100%\n".to_vec()]), vec![b"50%\r100%".to_vec()]);
+    }
+
+    #[test]
+    fn read_lines_passes_invalid_utf8_through() {
+
+        // `é` is split between the reads, and `0xff` is never valid.
+        let result = lines(vec![vec![b'x', 0xc3], vec![0xa9, 0xff, b'\n']]);
+
+        assert_eq!(result, vec![vec![b'x', 0xc3, 0xa9, 0xff]]);
+        assert_eq!(String::from_utf8_lossy(&result[0]), "x\u{e9}\u{fffd}");
+    }
+
+    #[test]
+    fn keep_tail_cuts_at_a_char_boundary() {
+
+        let mut text = "aé€".to_string();
+
+        keep_tail(&mut text, 4);
+
+        assert_eq!(text, "€");
+    }
+
+    #[test]
+    fn keep_tail_keeps_short_text() {
+
+        let mut text = "abc".to_string();
+
+        keep_tail(&mut text, 3);
+
+        assert_eq!(text, "abc");
+    }
+}
@@ -19,6 +19,9 @@ pub enum CustomErrorKind {
     #[allow(unused)]
     UserError(String),
     NotifyError(notify::Error),
+    GlobError(globset::Error),
+    IgnoreError(ignore::Error),
+    NixError(nix::Error),
 }
 
 #[derive(Debug)]
@@ -41,6 +44,9 @@ impl fmt::Debug for CustomErrorKind {
             SystemTimeError(err) => return err.fmt(f),
             UserError(err) => return err.fmt(f),
             NotifyError(err) => return err.fmt(f),
+            GlobError(err) => return err.fmt(f),
+            IgnoreError(err) => return err.fmt(f),
+            NixError(err) => return err.fmt(f),
         };
     }
 }
@@ -59,6 +65,9 @@ impl ToString for CustomErrorKind {
             SystemTimeError(err) => return err.to_string(),
             UserError(err) => return err.to_string(),
             NotifyError(err) => return err.to_string(),
+            GlobError(err) => return err.to_string(),
+            IgnoreError(err) => return err.to_string(),
+            NixError(err) => return err.to_string(),
         }
     }
 }
@@ -114,6 +123,33 @@ impl From<notify::Error> for CustomError {
     }
 }
 
+impl From<globset::Error> for CustomError {
+    fn from(err: globset::Error) -> Self {
+        CustomError {
+            kind: GlobError(err),
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+impl From<ignore::Error> for CustomError {
+    fn from(err: ignore::Error) -> Self {
+        CustomError {
+            kind: IgnoreError(err),
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+impl From<nix::Error> for CustomError {
+    fn from(err: nix::Error) -> Self {
+        CustomError {
+            kind: NixError(err),
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
 impl From<std::num::ParseIntError> for CustomError {
     fn from(err: std::num::ParseIntError) -> Self {
         CustomError {
@@ -186,6 +222,42 @@ impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, bool>>> for CustomErr
     }
 }
 
+impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, Vec<u32>>>> for CustomError {
+    fn from(err: std::sync::PoisonError<std::sync::MutexGuard<'_, Vec<u32>>>) -> Self {
+        CustomError {
+            kind: PoisonedError(format!("{:#?}", err)),
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, std::fs::File>>> for CustomError {
+    fn from(err: std::sync::PoisonError<std::sync::MutexGuard<'_, std::fs::File>>) -> Self {
+        CustomError {
+            kind: PoisonedError(format!("{:#?}", err)),
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, Vec<crate::changes::Change>>>> for CustomError {
+    fn from(err: std::sync::PoisonError<std::sync::MutexGuard<'_, Vec<crate::changes::Change>>>) -> Self {
+        CustomError {
+            kind: PoisonedError(format!("{:#?}", err)),
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+impl From<std::sync::PoisonError<std::sync::MutexGuard<'_, Vec<crate::per_file::FileResult>>>> for CustomError {
+    fn from(err: std::sync::PoisonError<std::sync::MutexGuard<'_, Vec<crate::per_file::FileResult>>>) -> Self {
+        CustomError {
+            kind: PoisonedError(format!("{:#?}", err)),
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
 pub type Result<T = ()> = std::result::Result<T, CustomError>;
 
 pub trait ResultExtensionsReplaceError<R> {
@@ -2,8 +2,10 @@ use std::sync::Mutex;
 use std::path::{PathBuf, Path};
 use std::fs::{File, OpenOptions};
 use std::io::{SeekFrom, Write, Seek};
+use std::os::unix::io::AsRawFd;
 
 use chrono::Utc;
+use nix::unistd::isatty;
 
 use super::prelude::*;
 
@@ -50,6 +52,17 @@ impl FileAppender {
         Ok(file_handle)
     }
 
+    /// Writes the buffered log lines to the disk.
+    pub fn flush(&self) -> Result {
+
+        let mut state = self.state.lock()?;
+
+        state.file_handle.flush()?;
+        state.file_handle.sync_all()?;
+
+        Ok(())
+    }
+
     fn roll_file(&self, state: &mut FileAppenderState) -> Result {
 
         let file_stem = self.config.file_path.file_stem_as_string()?;
@@ -93,6 +106,25 @@ impl FileAppender {
 
 pub struct ConsoleAppender;
 
+/// The colors of `Logger::log_colored`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Red,
+    Green,
+    Cyan,
+}
+
+impl Color {
+
+    fn ansi_code(&self) -> &'static str {
+        match self {
+            Color::Red => "31",
+            Color::Green => "32",
+            Color::Cyan => "36",
+        }
+    }
+}
+
 impl ConsoleAppender {
 
     pub fn new() -> ConsoleAppender {
@@ -114,6 +146,20 @@ impl ConsoleAppender {
 
         Ok(())
     }
+
+    /// Writes the message in color if stdout is a terminal, and as is otherwise.
+    pub fn writeln_colored(&self, message: &str, color: Color) -> Result {
+
+        let stdout = &mut ::std::io::stdout();
+
+        if isatty(stdout.as_raw_fd()).unwrap_or(false) {
+            write!(stdout, "\x1b[{}m{}\x1b[0m\n", color.ansi_code(), message)?;
+        } else {
+            write!(stdout, "{}\n", message)?;
+        }
+
+        Ok(())
+    }
 }
 
 pub struct InMemoryAppender {
@@ -177,6 +223,22 @@ impl Logger {
         Ok(())
     }
 
+    /// Like `log`, but the console gets the message in color. The log file never does.
+    pub fn log_colored(&self, message: &str, color: Color) -> Result {
+
+        let formatted_message = self.format_message(message)?;
+
+        let console_appender_result = self.console_appender.writeln_colored(&formatted_message, color);
+        let in_memory_appender_result = self.in_memory_appender.add_entry(message);
+        let file_appender_result = self.file_appender.writeln(&formatted_message);
+
+        console_appender_result?;
+        in_memory_appender_result?;
+        file_appender_result?;
+
+        Ok(())
+    }
+
     pub fn elog(&self, message: &str) -> Result {
 
         let formatted_message = self.format_message(message)?;
@@ -192,6 +254,12 @@ impl Logger {
         Ok(())
     }
 
+    /// Flushes the log file. Should be called before the process exits.
+    pub fn flush(&self) -> Result {
+
+        self.file_appender.flush()
+    }
+
     #[allow(unused)]
     pub fn get_logs(&self) -> Result<Vec<String>> {
 
@@ -0,0 +1,214 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::global::prelude::*;
+use crate::global::bash_shell::OutputFile;
+use crate::changes::Change;
+
+static RUNS_DIR_NAME: &str = "runs";
+static INDEX_FILE_NAME: &str = "index.jsonl";
+
+/// A line of `runs/index.jsonl`.
+#[derive(Serialize, Deserialize, Debug)]
+struct RunEntry {
+    /// The name of the log file without the extension, `<timestamp>-<rule index>-<number>`, e.g. `20190612-143005.123-0-2`.
+    id: String,
+    /// The name of the rule. Only set when there are multiple rules.
+    rule: Option<String>,
+    number: u64,
+    /// The paths that triggered the run.
+    triggers: Vec<PathBuf>,
+    /// RFC 3339.
+    started_at: String,
+    /// RFC 3339.
+    finished_at: String,
+    duration_ms: u64,
+    exit_code: i32,
+    success: bool,
+}
+
+/// Saves the output of the runs of a rule in `<state dir>/runs/<ID>.log` and lists them in `runs/index.jsonl`.
+#[derive(Clone)]
+pub struct RunRecorder {
+    runs_dir: PathBuf,
+    /// The position of the rule. The runs of different rules have the same numbers.
+    rule_index: usize,
+    rule: Option<String>,
+}
+
+impl RunRecorder {
+
+    pub fn new(state_dir: &Path, rule_index: usize, rule: Option<String>) -> RunRecorder {
+        RunRecorder {
+            runs_dir: state_dir.join(RUNS_DIR_NAME),
+            rule_index,
+            rule,
+        }
+    }
+
+    /// Creates the log file of a run.
+    pub fn start(&self, number: u64, changes: &[Change]) -> Result<RunRecord> {
+
+        let started_at = Utc::now();
+
+        let id = format!("{}-{}-{}", started_at.format("%Y%m%d-%H%M%S%.3f"), self.rule_index, number);
+
+        ::std::fs::create_dir_all(&self.runs_dir)?;
+
+        // Never overwrites the log of another run, e.g. of another watch-run with the same state directory.
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.runs_dir.join(format!("{}.log", id)))?;
+
+        Ok(RunRecord {
+            runs_dir: self.runs_dir.clone(),
+            id,
+            rule: self.rule.clone(),
+            number,
+            triggers: changes.iter().map(|x| x.path.clone()).collect_vec(),
+            started_at,
+            output_file: Arc::new(Mutex::new(file)),
+        })
+    }
+}
+
+/// An active run whose output is saved.
+pub struct RunRecord {
+    runs_dir: PathBuf,
+    id: String,
+    rule: Option<String>,
+    number: u64,
+    triggers: Vec<PathBuf>,
+    started_at: DateTime<Utc>,
+    pub output_file: OutputFile,
+}
+
+impl RunRecord {
+
+    /// Adds the run to the index.
+    pub fn finish(self, exit_code: i32, success: bool) -> Result {
+
+        let finished_at = Utc::now();
+
+        let entry = RunEntry {
+            id: self.id,
+            rule: self.rule,
+            number: self.number,
+            triggers: self.triggers,
+            started_at: self.started_at.to_rfc3339(),
+            finished_at: finished_at.to_rfc3339(),
+            duration_ms: (finished_at - self.started_at).num_milliseconds().max(0) as u64,
+            exit_code,
+            success,
+        };
+
+        // Every line is written at once in append mode, so the runners of different rules can share the file.
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.runs_dir.join(INDEX_FILE_NAME))?;
+
+        index_file.write_all(format!("{}\n", serde_json::to_string(&entry)?).as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// What `watch-run history` shows.
+pub struct HistoryOptions {
+    pub state_dir: PathBuf,
+    /// The number of runs listed.
+    pub count: usize,
+    /// The ID of the run whose output is printed, or `last`. Lists the runs if `None`.
+    pub run: Option<String>,
+}
+
+/// Lists the recent runs, or prints the output of one of them.
+pub fn print_history(options: &HistoryOptions) -> Result {
+
+    let runs_dir = options.state_dir.join(RUNS_DIR_NAME);
+
+    let entries = read_index(&runs_dir)?;
+
+    match &options.run {
+        Some(id) => {
+            let entry = if id == "last" {
+                entries.last()
+            } else {
+                entries.iter().find(|x| &x.id == id)
+            };
+
+            let entry = entry.ok_or_else(|| CustomError::user_error(&format!("There is no run `{}`.", id)))?;
+
+            println!("{}", describe_entry(entry));
+
+            let mut log_file = File::open(runs_dir.join(format!("{}.log", entry.id)))?;
+
+            ::std::io::copy(&mut log_file, &mut ::std::io::stdout())?;
+        },
+        None => {
+            if entries.is_empty() {
+                println!("No runs were recorded in `{}`.", runs_dir.get_as_string()?);
+            }
+
+            for entry in entries.iter().skip(entries.len().saturating_sub(options.count)) {
+                println!("{}", describe_entry(entry));
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Reads the index. Lines that can't be parsed, e.g. from a crash in the middle of a write, are skipped.
+fn read_index(runs_dir: &Path) -> Result<Vec<RunEntry>> {
+
+    let index_path = runs_dir.join(INDEX_FILE_NAME);
+
+    if !index_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+
+    for line in BufReader::new(File::open(index_path)?).lines() {
+
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn describe_entry(entry: &RunEntry) -> String {
+
+    let started_at = DateTime::parse_from_rfc3339(&entry.started_at)
+        .map(|x| x.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|_| entry.started_at.clone());
+
+    let rule = entry.rule.as_ref().map(|x| format!("[{}] ", x)).unwrap_or_default();
+
+    let triggers = entry.triggers.iter()
+        .map(|x| x.to_string_lossy().to_string())
+        .collect_vec()
+        .join(", ");
+
+    format!(
+        "{} | {} | {}{} | exit code {} | {} | {}",
+        entry.id,
+        started_at,
+        rule,
+        if entry.success { "passed" } else { "failed" },
+        entry.exit_code,
+        humantime::format_duration(Duration::from_millis(entry.duration_ms)),
+        if triggers.is_empty() { "no changed paths".to_string() } else { triggers }
+    )
+}
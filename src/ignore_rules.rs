@@ -0,0 +1,258 @@
+use std::path::{Path, PathBuf};
+
+use ignore::WalkBuilder;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::Regex;
+
+use crate::global::prelude::*;
+
+static IGNORE_FILE_NAMES: &[&str] = &[".gitignore", ".ignore"];
+
+/// The ignore rules from the `.gitignore`/`.ignore` files under the watch root and in its parent directories
+/// up to the root of the git repository, `.git/info/exclude` and the global git excludes.
+#[derive(Clone)]
+pub struct IgnoreRules {
+    root: PathBuf,
+    /// The ignore files ordered from the most nested to the least nested directory, then `.git/info/exclude`.
+    matchers: Vec<Gitignore>,
+    global: Gitignore,
+    /// The ignore files that the walk of the root does not find, whether they exist or not.
+    extra_files: Vec<PathBuf>,
+}
+
+impl IgnoreRules {
+
+    /// Loads all ignore files under `root` and in the parent directories that are in the same repository.
+    pub fn load(root: &Path) -> Result<IgnoreRules> {
+
+        let parents = repository_parents(root);
+
+        let mut extra_files: Vec<PathBuf> = parents.iter()
+            .flat_map(|x| IGNORE_FILE_NAMES.iter().map(move |name| x.join(name)))
+            .collect();
+
+        let mut matchers = load_matchers(root, &parents)?;
+
+        let repository_root = if root.join(".git").exists() { Some(root) } else { parents.last().map(|x| x.as_path()) };
+
+        if let Some(repository_root) = repository_root {
+
+            let exclude_path = repository_root.join(".git").join("info").join("exclude");
+
+            if exclude_path.is_file() {
+
+                let mut builder = GitignoreBuilder::new(repository_root);
+
+                if let Some(error) = builder.add(&exclude_path) {
+                    return Err(error.into());
+                }
+
+                matchers.push(builder.build()?);
+            }
+
+            extra_files.push(exclude_path);
+        }
+
+        let mut global = Gitignore::empty();
+
+        if let Some(global_path) = global_excludes_path() {
+
+            if global_path.is_file() {
+
+                let mut builder = GitignoreBuilder::new("");
+
+                let result = match builder.add(&global_path) {
+                    Some(error) => Err(error),
+                    None => builder.build(),
+                };
+
+                match result {
+                    Ok(x) => global = x,
+                    Err(error) => elog!("Failed to load the global git excludes: {}", error),
+                }
+            }
+
+            extra_files.push(global_path);
+        }
+
+        Ok(IgnoreRules {
+            root: root.to_path_buf(),
+            matchers,
+            global,
+            extra_files,
+        })
+    }
+
+    /// Reloads the ignore files. Should be called when one of them changes.
+    pub fn reload(&mut self) -> Result {
+
+        *self = IgnoreRules::load(&self.root)?;
+
+        Ok(())
+    }
+
+    /// Returns `true` if `path` is one of the ignore files that the rules are loaded from, or a new one under the root.
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+
+        if self.extra_files.iter().any(|x| x == path) {
+            return true;
+        }
+
+        path.starts_with(&self.root) && path.file_name()
+            .map(|x| IGNORE_FILE_NAMES.iter().any(|name| x == *name))
+            .unwrap_or(false)
+    }
+
+    /// The ignore files outside of the root, and `.git/info/exclude`. They need their own watches.
+    pub fn extra_files(&self) -> &[PathBuf] {
+
+        &self.extra_files
+    }
+
+    /// Returns `true` if `path` or one of its parent directories is ignored.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+
+        let relative_path = match path.strip_prefix(&self.root) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+
+        if relative_path.components().any(|x| x.as_os_str() == ".git") {
+            return true;
+        }
+
+        let is_dir = path.is_dir();
+
+        for matcher in &self.matchers {
+
+            if !path.starts_with(matcher.path()) {
+                continue;
+            }
+
+            let matched = matcher.matched_path_or_any_parents(path, is_dir);
+
+            if matched.is_ignore() {
+                return true;
+            }
+
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+
+        relative_path.ancestors()
+            .filter(|x| !x.as_os_str().is_empty())
+            .any(|x| self.global.matched(x, x == relative_path && is_dir).is_ignore())
+    }
+}
+
+fn load_matchers(root: &Path, parents: &[PathBuf]) -> Result<Vec<Gitignore>> {
+
+    let mut directories = Vec::new();
+
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .filter_entry(|x| x.file_name() != ".git")
+        .build();
+
+    for entry_result in walker {
+
+        let entry = entry_result?;
+
+        if entry.file_type().map(|x| x.is_dir()).unwrap_or(false) {
+            directories.push(entry.into_path());
+        }
+    }
+
+    directories.extend(parents.iter().cloned());
+
+    let mut matchers = Vec::new();
+
+    for directory in directories.into_iter().order_by_desc(|x| x.components().count()) {
+
+        let mut builder = GitignoreBuilder::new(&directory);
+        let mut has_files = false;
+
+        for file_name in IGNORE_FILE_NAMES {
+
+            let file_path = directory.join(file_name);
+
+            if file_path.is_file() {
+
+                if let Some(error) = builder.add(&file_path) {
+                    return Err(error.into());
+                }
+
+                has_files = true;
+            }
+        }
+
+        if has_files {
+            matchers.push(builder.build()?);
+        }
+    }
+
+    Ok(matchers)
+}
+
+/// The parent directories of `root` up to the root of its git repository, like git reads them.
+/// Empty if `root` is the repository root or is not in a repository.
+fn repository_parents(root: &Path) -> Vec<PathBuf> {
+
+    if root.join(".git").exists() {
+        return Vec::new();
+    }
+
+    let mut parents = Vec::new();
+
+    for parent in root.ancestors().skip(1) {
+
+        parents.push(parent.to_path_buf());
+
+        if parent.join(".git").exists() {
+            return parents;
+        }
+    }
+
+    Vec::new()
+}
+
+/// The `core.excludesFile` from `~/.gitconfig` or `$XDG_CONFIG_HOME/git/config`, or the default `$XDG_CONFIG_HOME/git/ignore`.
+fn global_excludes_path() -> Option<PathBuf> {
+
+    lazy_static::lazy_static! {
+        static ref EXCLUDES_FILE_RE: Regex = Regex::new(r"(?im)^\s*excludesfile\s*=\s*(.+?)\s*$").unwrap();
+    }
+
+    let home = ::std::env::var_os("HOME").filter(|x| !x.is_empty()).map(PathBuf::from);
+
+    let config_home = ::std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|x| x.join(".config")));
+
+    let config_files = vec![
+        home.as_ref().map(|x| x.join(".gitconfig")),
+        config_home.as_ref().map(|x| x.join("git").join("config")),
+    ];
+
+    for config_file in config_files.into_iter().flatten() {
+
+        let contents = match ::std::fs::read_to_string(&config_file) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+
+        if let Some(captures) = EXCLUDES_FILE_RE.captures(&contents) {
+
+            let value = &captures[1];
+
+            return match (value.starts_with("~/"), &home) {
+                (true, Some(home)) => Some(home.join(&value[2..])),
+                _ => Some(PathBuf::from(value)),
+            };
+        }
+    }
+
+    config_home.map(|x| x.join("git").join("ignore"))
+}
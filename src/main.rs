@@ -2,127 +2,574 @@
 
 #[macro_use]
 mod global;
-
-use std::sync::mpsc::channel;
-use std::time::Duration;
-use std::sync::{Mutex, Arc};
+mod options;
+mod filters;
+mod ignore_rules;
+mod runner;
+mod scheduler;
+mod changes;
+mod per_file;
+mod rules;
+mod content_hash;
+mod command_line;
+mod roots;
+mod backend;
+mod snapshot;
+mod terminal;
+mod signals;
+mod exit_policy;
+mod state;
+mod history;
+
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
 use std::thread::JoinHandle;
-use std::path::{PathBuf};
 
-use notify::{Watcher, RecursiveMode, watcher, DebouncedEvent};
+use nix::sys::signal::Signal;
+use notify::DebouncedEvent;
 
 use crate::global::prelude::*;
-
-static WATCH_DELAY: u64 = 1000;
+use crate::options::{Mode, Options, parse_options, USAGE_ERROR_EXIT_CODE};
+use crate::runner::{Runner, RunnerConfig, PerFileConfig, RunEvent, ChangesDir};
+use crate::rules::Rule;
+use crate::content_hash::ContentHashCache;
+use crate::scheduler::{Scheduler, SchedulerEvent};
+use crate::changes::{Change, DebounceEventExtensions};
+use crate::roots::{WatchRoot, find_root, check_roots, watch_ignore_files, ROOT_CHECK_INTERVAL};
+use crate::backend::{start_watcher, describe_watcher_error};
+use crate::snapshot::Snapshot;
+use crate::filters::EventKindFilter;
+use crate::terminal::{KeyCommand, start_keyboard, clear_screen};
+use crate::signals::{wait_for_signals, signal_exit_code};
+use crate::exit_policy::ExitPolicy;
+use crate::state::{InitialRun, StateStore};
+use crate::history::{RunRecorder, print_history};
+
+/// What the watch thread reacts to.
+enum WatchEvent {
+    File(DebouncedEvent),
+    Key(KeyCommand),
+    /// SIGINT or SIGTERM.
+    Signal(Signal),
+    /// A run of the rule with the index started or finished.
+    Run(usize, RunEvent),
+}
 
 fn main() {
+
+    let mode = match parse_options() {
+        Ok(x) => x,
+        Err(error) => {
+            eprintln!("{}", error.kind.to_string());
+            ::std::process::exit(USAGE_ERROR_EXIT_CODE);
+        },
+    };
+
     global::initialize();
-    main_result().crash_on_error();
+
+    let exit_code = match mode {
+        Mode::Watch(options) => main_result(*options),
+        Mode::History(options) => print_history(&options).map(|_| 0),
+    }.crash_on_error();
+
+    let _ = logger().flush();
+
+    ::std::process::exit(exit_code);
 }
 
-fn main_result() -> Result {
+/// Returns the exit code of watch-run.
+fn main_result(options: Options) -> Result<i32> {
 
-    let args = ::std::env::args_os()
-        .skip(1)
-        .map_result(|x| x.get_as_string())?
+    let mut roots = options.roots.iter()
+        .map_result(|x| WatchRoot::new(x, &options.filter))?
         .collect_vec();
 
-    if args.len() < 2 {
-        log!("Error: not enough parameters.");
-        ::std::process::exit(1);
-    }
+    let per_file = if options.per_file {
+        Some(PerFileConfig {
+            jobs: options.jobs,
+        })
+    } else {
+        None
+    };
 
-    let watch_path = ::std::fs::canonicalize(args[0].clone())?.get_as_string()?;
+    let mut rules = options.rules.iter()
+        .map_result(|x| Rule::new(x))?
+        .collect_vec();
 
-    let command = args.into_iter().skip(1).collect_vec().join(" ");
+    if let Some(command) = options.command.clone() {
+        rules.push(Rule::catch_all(command));
+    }
+
+    let show_rule_names = rules.len() > 1;
 
-    log!("Watching `{}` ...", watch_path);
+    // Dropped after the runners have stopped.
+    let changes_dir = ChangesDir::create()?;
 
     let (sender, receiver) = channel();
 
-    let mut watcher = watcher(sender, Duration::from_millis(WATCH_DELAY))?;
-    watcher.watch(&watch_path, RecursiveMode::Recursive)?;
+    let mut routes = Vec::new();
+    let mut run_threads = RunThreads {
+        senders: Vec::new(),
+        threads: Vec::new(),
+    };
+
+    for (index, rule) in rules.into_iter().enumerate() {
+
+        let (event_sender, event_receiver) = channel();
+
+        let run_sender = sender.clone();
+
+        let name = if show_rule_names { Some(rule.name.clone()) } else { None };
+
+        let recorder = if options.record {
+            Some(RunRecorder::new(&options.state_dir, index, name.clone()))
+        } else {
+            None
+        };
+
+        let runner = Runner::new(RunnerConfig {
+            name,
+            index,
+            changes_dir: changes_dir.path.clone(),
+            command: rule.command.clone(),
+            shell: options.shell.clone(),
+            forward_stdin: options.forward_stdin,
+            clear: options.clear,
+            timeout: options.timeout,
+            kill_grace: options.kill_grace,
+            policy: options.busy_policy,
+            per_file: per_file.clone(),
+            on_run: Some(Box::new(move |event| {
+                let _ = run_sender.send(WatchEvent::Run(index, event));
+            })),
+            recorder,
+        }, event_sender.clone());
+
+        let scheduler = Scheduler::new(options.scheduler.clone(), event_receiver, runner);
+
+        run_threads.threads.push(::std::thread::spawn(move || scheduler.run()));
+        run_threads.senders.push(event_sender.clone());
+
+        routes.push((rule, event_sender));
+    }
 
-    let flag = Arc::new(Mutex::new(false));
+    let mut change_handler = ChangeHandler {
+        event_kinds: options.event_kinds,
+        content_hashes: options.content_hash.map(ContentHashCache::new),
+        show_root_names: roots.len() > 1,
+        state_dir: options.state_dir.clone(),
+    };
 
-    let watch_flag = flag.clone();
+    change_handler.seed_content_hashes(&roots)?;
 
-    let watch_thread: JoinHandle<Result> = ::std::thread::spawn(move || {
+    let (file_sender, file_receiver) = channel();
 
-        loop {
-            match receiver.recv() {
-                Ok(event) => {
+    let mut watcher = start_watcher(&options.backend, file_sender, &roots)?;
 
-                    if let Some(path) = event.get_path() {
+    let mut ignore_directories = Vec::new();
 
-                        log!("Change: {}", path.get_as_string()?);
-                    }
+    watch_ignore_files(&roots, &mut watcher, &mut ignore_directories)?;
 
-                    let mut value = watch_flag.lock()?;
+    let file_event_sender = sender.clone();
+    let signal_sender = sender.clone();
 
-                    *value = true;
-                },
-                Err(error) => elog!("{:#?}", error),
+    wait_for_signals(move |signal| {
+        let _ = signal_sender.send(WatchEvent::Signal(signal));
+    })?;
+
+    ::std::thread::spawn(move || {
+        for event in file_receiver {
+            if file_event_sender.send(WatchEvent::File(event)).is_err() {
+                break;
             }
         }
     });
 
-    let run_flag = flag.clone();
+    // The keys would be read from the same stdin that the command gets.
+    let raw_mode = if options.forward_stdin {
+        None
+    } else {
+        start_keyboard(move |command| {
+            sender.send(WatchEvent::Key(command)).is_ok() && command != KeyCommand::Quit
+        })?
+    };
+
+    if raw_mode.is_some() {
+        log!("Keys: r/Enter - run, p - pause, c - clear, k - kill, q - quit.");
+    }
+
+    let filter_config = options.filter;
 
-    let run_thread: JoinHandle<Result> = ::std::thread::spawn(move || {
+    for root in &roots {
+
+        if !root.exists() {
+            log!("`{}` does not exist yet. Waiting for it to be created ...", root.name);
+        } else if root.recursive {
+            log!("Watching `{}` ...", root.path.get_as_string()?);
+        } else {
+            log!("Watching `{}` (without subdirectories) ...", root.path.get_as_string()?);
+        }
+    }
+
+    let mut state_store = if options.initial_run == InitialRun::IfChanged {
+        Some(StateStore::load(&options.state_dir)?)
+    } else {
+        None
+    };
+
+    if options.initial_run != InitialRun::Never {
+
+        for (index, (rule, _)) in routes.iter().enumerate() {
+
+            let is_changed = match &mut state_store {
+                Some(state_store) => state_store.is_changed(index, rule, &roots),
+                None => true,
+            };
+
+            if is_changed {
+                send_change(&routes[index..=index], None)?;
+            } else {
+                let log_prefix = if show_rule_names { format!("[{}] ", rule.name) } else { String::new() };
+
+                log!("{}Nothing changed since the last successful run. Skipping the initial run.", log_prefix);
+            }
+        }
+    }
+
+    let mut exit_policy = ExitPolicy::new(options.exit_policy);
+
+    let watch_thread: JoinHandle<Result<i32>> = ::std::thread::spawn(move || {
+
+        let mut error_count: u64 = 0;
+        let mut paused = false;
 
         loop {
-            ::std::thread::sleep(Duration::from_millis(WATCH_DELAY));
+            let event = match receiver.recv_timeout(ROOT_CHECK_INTERVAL) {
+                Ok(x) => Some(x),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return Err(CustomError::from_message("The watcher stopped for some reason.")),
+            };
+
+            if check_roots(&mut roots, &mut watcher, &filter_config)? {
+
+                change_handler.seed_content_hashes(&roots)?;
+
+                if let Some(state_store) = &mut state_store {
+                    state_store.clear_matches();
+                }
+
+                if !paused {
+                    exit_policy.on_change();
+
+                    send_change(&routes, None)?;
+                }
+            }
+
+            watch_ignore_files(&roots, &mut watcher, &mut ignore_directories)?;
+
+            if let Some(exit_code) = exit_policy.check_idle()? {
+
+                send_to_all(&routes, SchedulerEvent::Shutdown(Signal::SIGTERM))?;
+
+                return Ok(exit_code);
+            }
+
+            let event = match event {
+                Some(WatchEvent::File(x)) => x,
+                Some(WatchEvent::Key(command)) => {
+                    match command {
+                        KeyCommand::Rerun => {
+                            log!("Running all commands.");
+
+                            exit_policy.on_change();
+
+                            send_change(&routes, None)?;
+                        },
+                        KeyCommand::Pause => {
+                            paused = !paused;
+
+                            if paused {
+                                log!("Paused. Changes are ignored until `p` is pressed again.");
+                            } else {
+                                log!("Resumed.");
+                            }
+                        },
+                        KeyCommand::Clear => clear_screen()?,
+                        KeyCommand::Kill => {
+                            log!("Killing the running commands.");
+
+                            send_to_all(&routes, SchedulerEvent::Kill)?;
+                        },
+                        KeyCommand::Quit => {
+                            log!("Quitting ...");
+
+                            send_to_all(&routes, SchedulerEvent::Shutdown(Signal::SIGTERM))?;
+
+                            return Ok(0);
+                        },
+                    }
+
+                    continue;
+                },
+                Some(WatchEvent::Signal(signal)) => {
+                    log!("Received {}. Stopping the commands ...", signal);
+
+                    send_to_all(&routes, SchedulerEvent::Shutdown(signal))?;
+
+                    return Ok(signal_exit_code(signal));
+                },
+                Some(WatchEvent::Run(rule_index, run_event)) => {
+
+                    if let Some(state_store) = &mut state_store {
+                        state_store.on_run(rule_index, &routes[rule_index].0, run_event, &roots)?;
+                    }
+
+                    if let Some(exit_code) = exit_policy.on_run(run_event)? {
+
+                        send_to_all(&routes, SchedulerEvent::Shutdown(Signal::SIGTERM))?;
+
+                        return Ok(exit_code);
+                    }
+
+                    continue;
+                },
+                None => continue,
+            };
+
+            match &event {
+                DebouncedEvent::Rescan => {
+                    log!("The watcher lost events. Rescanning ...");
+
+                    let mut change_count = 0;
+
+                    for root in roots.iter_mut().filter(|x| x.exists()) {
+
+                        let snapshot = Snapshot::scan(&root.path, root.recursive, &root.filter);
+                        let changes = root.snapshot.diff(&root.base, &snapshot);
+                        root.snapshot = snapshot;
+
+                        for change in changes {
+
+                            if root.filter.handle_change(&change.path)? {
+                                if let Some(state_store) = &mut state_store {
+                                    state_store.clear_matches();
+                                }
+                            }
+
+                            if !paused && change_handler.accept(root, &change, false)? {
+
+                                change_count += 1;
+
+                                exit_policy.on_change();
+
+                                send_change(&routes, Some(change))?;
+                            }
+                        }
+                    }
+
+                    log!("The rescan found {} change(s).", change_count);
+                },
+                DebouncedEvent::Error(error, path) => {
+
+                    // The polling watcher reports a missing root on every scan until `check_roots` notices it.
+                    let is_missing_root = path.as_ref()
+                        .and_then(|x| find_root(&mut roots, x))
+                        .map(|x| !x.exists())
+                        .unwrap_or(false);
 
-            let mut value = run_flag.lock()?;
+                    if !is_missing_root {
 
-            if *value {
+                        error_count += 1;
 
-                run_command(&command)?;
+                        match path {
+                            Some(path) => elog!("Watcher error #{} at `{}`: {}", error_count, path.get_as_string()?, describe_watcher_error(error)),
+                            None => elog!("Watcher error #{}: {}", error_count, describe_watcher_error(error)),
+                        }
+                    }
+                },
+                _ => {
+                    if !event.is_notice() && reload_ignore_rules(&mut roots, &event)? {
+                        if let Some(state_store) = &mut state_store {
+                            state_store.clear_matches();
+                        }
+                    }
+
+                    let root = match event.get_path().and_then(|x| find_root(&mut roots, &x)) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+
+                    let change = match event.get_change(&root.base) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+
+                    if !event.is_notice() {
+
+                        if let DebouncedEvent::Rename(from, _) = &event {
+                            root.snapshot.update(from);
+                        }
+
+                        root.snapshot.update(&change.path);
+                    }
+
+                    if !paused && change_handler.accept(root, &change, event.is_notice())? {
 
-                *value = false;
+                        exit_policy.on_change();
+
+                        send_change(&routes, Some(change))?;
+                    }
+                },
             }
         }
     });
 
-    watch_thread.join().replace_error(||
+    let exit_code = watch_thread.join().replace_error(||
         CustomError::from_message("The receiver thread failed for some reason."))??;
 
-    run_thread.join().replace_error(||
-        CustomError::from_message("The run thread failed for some reason."))??;
+    run_threads.join()?;
 
-    Ok(())
+    Ok(exit_code)
 }
 
-fn run_command(command: &str) -> Result {
+/// The scheduler threads of the rules. The watch thread stops them before it returns.
+/// If `main_result` fails instead, dropping this stops them, so the commands don't outlive watch-run.
+struct RunThreads {
+    senders: Vec<Sender<SchedulerEvent>>,
+    threads: Vec<JoinHandle<Result>>,
+}
 
-    let result = crate::global::bash_shell::exec(&command);
+impl RunThreads {
 
-    match result {
-        Ok(_) => (),
-        Err(err) => elog!("{:#?}", err)
+    fn join(mut self) -> Result {
+
+        for thread in self.threads.drain(..) {
+            thread.join().replace_error(||
+                CustomError::from_message("The run thread failed for some reason."))??;
+        }
+
+        Ok(())
     }
+}
 
-    Ok(())
+impl Drop for RunThreads {
+    fn drop(&mut self) {
+
+        if self.threads.is_empty() {
+            return;
+        }
+
+        for sender in &self.senders {
+            let _ = sender.send(SchedulerEvent::Shutdown(Signal::SIGTERM));
+        }
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Decides which changes trigger a run and logs them.
+struct ChangeHandler {
+    event_kinds: EventKindFilter,
+    content_hashes: Option<ContentHashCache>,
+    show_root_names: bool,
+    /// The files of watch-run itself never trigger a run.
+    state_dir: PathBuf,
+}
+
+impl ChangeHandler {
+
+    /// Hashes the files of the roots with `--content-hash`, so that rewriting a file with the same content is dropped from the start.
+    fn seed_content_hashes(&mut self, roots: &[WatchRoot]) -> Result {
+
+        if let Some(content_hashes) = &mut self.content_hashes {
+
+            for root in roots {
+                content_hashes.seed(root.snapshot.files().filter(|x| root.filter.is_match(x)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the change passes the filters of its root, `--on` and `--content-hash`.
+    fn accept(&mut self, root: &mut WatchRoot, change: &Change, is_notice: bool) -> Result<bool> {
+
+        if change.path.starts_with(&self.state_dir) {
+            return Ok(false);
+        }
+
+        if !self.event_kinds.is_match(change.kind, is_notice) || !root.filter.is_match(&change.path) {
+            return Ok(false);
+        }
+
+        if let Some(content_hashes) = &mut self.content_hashes {
+
+            if !content_hashes.is_changed(change)? {
+
+                log!("Unchanged content: {}", change.path.get_as_string()?);
+
+                return Ok(false);
+            }
+        }
+
+        if self.show_root_names {
+            log!("Change ({}) in `{}`: {}", change.kind, root.name, change.path.get_as_string()?);
+        } else {
+            log!("Change ({}): {}", change.kind, change.path.get_as_string()?);
+        }
+
+        Ok(true)
+    }
 }
 
-trait DebounceEventExtensions {
-    fn get_path(&self) -> Option<PathBuf>;
+/// Reloads the ignore rules of every root that reads the changed file. The file can be outside of the roots.
+/// Returns `true` if any rules were reloaded.
+fn reload_ignore_rules(roots: &mut [WatchRoot], event: &DebouncedEvent) -> Result<bool> {
+
+    let mut paths = event.get_path().into_iter().collect::<Vec<_>>();
+
+    if let DebouncedEvent::Rename(from, _) = event {
+        paths.push(from.clone());
+    }
+
+    let mut reloaded = false;
+
+    for root in roots.iter_mut() {
+        for path in &paths {
+            reloaded |= root.filter.handle_change(path)?;
+        }
+    }
+
+    Ok(reloaded)
 }
 
-impl DebounceEventExtensions for DebouncedEvent {
-
-    fn get_path(&self) -> Option<PathBuf> {
-        match self {
-            DebouncedEvent::NoticeWrite(x) => Some(x.clone()),
-            DebouncedEvent::NoticeRemove(x) => Some(x.clone()),
-            DebouncedEvent::Create(x) => Some(x.clone()),
-            DebouncedEvent::Write(x) => Some(x.clone()),
-            DebouncedEvent::Chmod(x) => Some(x.clone()),
-            DebouncedEvent::Remove(x) => Some(x.clone()),
-            DebouncedEvent::Rename(_, x) => Some(x.clone()),
-            DebouncedEvent::Rescan => None,
-            DebouncedEvent::Error(_, x) => x.clone(),
+/// Sends the change to the rules that match it. `None` goes to every rule.
+fn send_change(routes: &[(Rule, Sender<SchedulerEvent>)], change: Option<Change>) -> Result {
+
+    for (rule, event_sender) in routes {
+
+        if let Some(change) = &change {
+
+            if !rule.is_match(change) {
+                continue;
+            }
         }
+
+        event_sender.send(SchedulerEvent::Change(change.clone())).replace_error(||
+            CustomError::from_message("The scheduler stopped for some reason."))?;
     }
+
+    Ok(())
+}
+
+fn send_to_all(routes: &[(Rule, Sender<SchedulerEvent>)], event: SchedulerEvent) -> Result {
+
+    for (_, event_sender) in routes {
+        event_sender.send(event.clone()).replace_error(||
+            CustomError::from_message("The scheduler stopped for some reason."))?;
+    }
+
+    Ok(())
 }
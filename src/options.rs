@@ -0,0 +1,521 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind};
+
+use crate::global::prelude::*;
+use crate::runner::BusyPolicy;
+use crate::scheduler::{SchedulerConfig, Edge};
+use crate::rules::{RuleConfig, read_rules_file};
+use crate::filters::{EventKindFilter, check_globs};
+use crate::content_hash::ContentHashConfig;
+use crate::global::bash_shell::{ShellConfig, Timeout};
+use crate::roots::{WatchRootConfig, FilterConfig};
+use crate::backend::{BackendConfig, BackendKind};
+use crate::exit_policy::ExitPolicyConfig;
+use crate::state::InitialRun;
+use crate::history::HistoryOptions;
+use crate::command_line::CommandLine;
+
+/// The exit code for invalid command line arguments.
+pub static USAGE_ERROR_EXIT_CODE: i32 = 2;
+
+/// What watch-run was asked to do.
+pub enum Mode {
+    Watch(Box<Options>),
+    /// `watch-run history`.
+    History(HistoryOptions),
+}
+
+/// The parsed command line options.
+pub struct Options {
+    pub roots: Vec<WatchRootConfig>,
+    /// The command after the watch path. `None` if only rules are used.
+    pub command: Option<CommandLine>,
+    pub backend: BackendConfig,
+    /// `None` if `--no-shell` is used.
+    pub shell: Option<ShellConfig>,
+    pub forward_stdin: bool,
+    pub clear: bool,
+    /// Set if `--timeout` is used.
+    pub timeout: Option<Timeout>,
+    /// How long the commands have to exit after SIGTERM, on a timeout or on shutdown.
+    pub kill_grace: Duration,
+    pub exit_policy: ExitPolicyConfig,
+    pub initial_run: InitialRun,
+    /// Where watch-run keeps its files. Changes under it are ignored.
+    pub state_dir: PathBuf,
+    /// Save the output of every run in the state directory.
+    pub record: bool,
+    pub rules: Vec<RuleConfig>,
+    pub filter: FilterConfig,
+    pub event_kinds: EventKindFilter,
+    /// Set if `--content-hash` is used.
+    pub content_hash: Option<ContentHashConfig>,
+    pub busy_policy: BusyPolicy,
+    pub scheduler: SchedulerConfig,
+    pub per_file: bool,
+    pub jobs: usize,
+}
+
+/// Parses the command line arguments of the current process.
+/// Prints the help or the version and exits if requested.
+/// All errors are usage errors and should exit with `USAGE_ERROR_EXIT_CODE`.
+pub fn parse_options() -> Result<Mode> {
+
+    let args = ::std::env::args_os().collect_vec();
+
+    // Not a clap subcommand, because clap would not tell it apart from the `<PATH>` argument.
+    // A directory named `history` can still be watched with `./history` or `--watch history`.
+    if args.get(1).map(|x| x == "history").unwrap_or(false) {
+
+        let matches = get_matches(create_history_app(), &args[1..])?;
+
+        check_utf8(&args[1..], &[])?;
+
+        parse_history_matches(&matches).map(Mode::History).map_err(to_usage_error)
+    } else {
+
+        let matches = get_matches(create_app(), &args)?;
+
+        check_utf8(&args, &matches.indices_of("args").map(|x| x.collect_vec()).unwrap_or_default())?;
+
+        parse_matches(&matches).map(|x| Mode::Watch(Box::new(x))).map_err(to_usage_error)
+    }
+}
+
+/// clap panics when an option that is read as a string is not valid UTF-8, so only the arguments at `allowed_indices`,
+/// the ones of the command, may be other bytes. The command gets them as they are, like file names in other encodings.
+fn check_utf8(args: &[OsString], allowed_indices: &[usize]) -> Result {
+
+    let is_valid = args.iter()
+        .enumerate()
+        .all(|(index, arg)| arg.to_str().is_some() || allowed_indices.contains(&index));
+
+    if !is_valid {
+        return Err(CustomError::user_error("error: The options must be valid UTF-8."));
+    }
+
+    Ok(())
+}
+
+fn to_usage_error(error: CustomError) -> CustomError {
+
+    CustomError::user_error(&format!("error: {}", error.kind.to_string()))
+}
+
+fn get_matches(app: App<'static, 'static>, args: &[OsString]) -> Result<ArgMatches<'static>> {
+
+    match app.get_matches_from_safe(args) {
+        Ok(x) => Ok(x),
+        Err(error) => match error.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => error.exit(),
+            _ => Err(CustomError::user_error(&error.message)),
+        },
+    }
+}
+
+fn create_history_app() -> App<'static, 'static> {
+
+    App::new("watch-run history")
+        .bin_name("watch-run history")
+        .about("Lists the runs saved with `--record`, or prints the output of one of them")
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(Arg::with_name("state-dir")
+            .long("state-dir")
+            .value_name("DIR")
+            .help("The state directory that the runs were saved in.")
+            .default_value(".watch-run"))
+        .arg(Arg::with_name("count")
+            .long("count")
+            .short("n")
+            .value_name("N")
+            .help("The number of runs listed.")
+            .default_value("20"))
+        .arg(Arg::with_name("run")
+            .value_name("ID")
+            .help("Prints the output of this run. `last` is the most recent one."))
+}
+
+fn parse_history_matches(matches: &ArgMatches) -> Result<HistoryOptions> {
+
+    Ok(HistoryOptions {
+        state_dir: ::std::env::current_dir()?.join(matches.value_of("state-dir").unwrap_or_default()),
+        count: matches.value_of("count").unwrap_or_default().parse()?,
+        run: matches.value_of("run").map(|x| x.to_string()),
+    })
+}
+
+fn create_app() -> App<'static, 'static> {
+
+    App::new("watch-run")
+        .version(clap::crate_version!())
+        .about("Watches a directory and runs a command if files/directories change")
+        .usage("watch-run [OPTIONS] <PATH> [--] <COMMAND>...\n    \
+                watch-run [OPTIONS] --watch <PATH>... [--] <COMMAND>...\n    \
+                watch-run history [OPTIONS] [ID]")
+        .setting(AppSettings::TrailingVarArg)
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(Arg::with_name("watch")
+            .long("watch")
+            .short("w")
+            .value_name("PATH")
+            .help("A directory or file to watch, including subdirectories. Can be repeated. \
+                   If neither `--watch` nor `--watch-flat` is used, the first positional argument is the directory.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("watch-flat")
+            .long("watch-flat")
+            .short("W")
+            .value_name("PATH")
+            .help("A directory to watch without its subdirectories, or a file. Can be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("delay")
+            .long("delay")
+            .value_name("DURATION")
+            .help("How long the watcher merges the raw events of a single file system operation.")
+            .default_value("50ms"))
+        .arg(Arg::with_name("backend")
+            .long("backend")
+            .value_name("BACKEND")
+            .help("How to watch for changes: native, poll, or auto, which falls back to polling if the native watcher fails to start.")
+            .possible_values(&["auto", "native", "poll"])
+            .default_value("auto"))
+        .arg(Arg::with_name("poll")
+            .long("poll")
+            .value_name("INTERVAL")
+            .help("Polls for changes every INTERVAL instead of using the native watcher. \
+                   Needed on NFS, SSHFS and bind mounts. With `--backend`, only sets the interval.")
+            .default_value("1s"))
+        .arg(Arg::with_name("shell")
+            .long("shell")
+            .value_name("SHELL")
+            .help("The shell that runs the command, e.g. bash, sh, zsh, fish or dash.")
+            .default_value("bash"))
+        .arg(Arg::with_name("shell-preamble")
+            .long("shell-preamble")
+            .value_name("SCRIPT")
+            .help("Runs before the command in the shell. \
+                   Defaults to `set -exu` for bash, sh, dash, zsh and ksh, and to nothing for other shells.")
+            .allow_hyphen_values(true))
+        .arg(Arg::with_name("no-shell")
+            .long("no-shell")
+            .help("Runs the program directly, without a shell. A single argument is split on whitespace.")
+            .conflicts_with_all(&["shell", "shell-preamble"]))
+        .arg(Arg::with_name("stdin")
+            .long("stdin")
+            .help("Passes the stdin of watch-run to the command. Not used in per-file mode."))
+        .arg(Arg::with_name("clear")
+            .long("clear")
+            .help("Clears the screen before every run."))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("DURATION")
+            .help("Stops a run that takes longer than this. The command's process group gets SIGTERM, then SIGKILL after `--kill-grace`."))
+        .arg(Arg::with_name("kill-grace")
+            .long("kill-grace")
+            .value_name("DURATION")
+            .help("How long a command has to exit after SIGTERM, on a timeout, a restart, `k` or when watch-run stops, before it gets SIGKILL.")
+            .default_value("5s"))
+        .arg(Arg::with_name("initial-run")
+            .long("initial-run")
+            .help("Runs the commands once right after the watcher starts."))
+        .arg(Arg::with_name("initial-run-if-changed")
+            .long("initial-run-if-changed")
+            .conflicts_with("initial-run")
+            .help("Like `--initial-run`, but skipped for the rules whose watched files and arguments did not change \
+                   since their last successful run. The state is kept in `--state-dir`."))
+        .arg(Arg::with_name("state-dir")
+            .long("state-dir")
+            .value_name("DIR")
+            .help("Where watch-run keeps its files. Changes under it are ignored.")
+            .default_value(".watch-run"))
+        .arg(Arg::with_name("record")
+            .long("record")
+            .help("Saves the output of every run in `<state dir>/runs/` and lists the runs in `runs/index.jsonl`. \
+                   See `watch-run history`."))
+        .arg(Arg::with_name("once")
+            .long("once")
+            .conflicts_with("exit-after")
+            .help("Waits for a change, runs the command once and exits with its exit code. The same as `--exit-after 1`."))
+        .arg(Arg::with_name("exit-after")
+            .long("exit-after")
+            .value_name("N")
+            .help("Exits after N runs, with the exit code of the last one."))
+        .arg(Arg::with_name("idle-timeout")
+            .long("idle-timeout")
+            .value_name("DURATION")
+            .help("Exits when nothing changes and no command runs for this long, with the exit code of the last run or 0."))
+        .arg(Arg::with_name("include")
+            .long("include")
+            .value_name("GLOB")
+            .help("Only changes to paths matching this pattern trigger a run. Can be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("GLOB")
+            .help("Changes to paths matching this pattern are ignored. Can be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("gitignore")
+            .long("gitignore")
+            .help("Ignores changes to paths matched by the `.gitignore` and `.ignore` files of the watched directory and its parents \
+                   up to the repository root, by `.git/info/exclude` and by the global git excludes."))
+        .arg(Arg::with_name("on")
+            .long("on")
+            .value_name("KINDS")
+            .help("A comma separated list of the event kinds that count as a change: \
+                   create, write, remove, rename, chmod. Add `notice` to also react to the early write/remove notices.")
+            .default_value("create,write,remove,rename,chmod"))
+        .arg(Arg::with_name("content-hash")
+            .long("content-hash")
+            .help("Ignores writes that do not change the content of a file, by keeping a hash of every changed file."))
+        .arg(Arg::with_name("hash-max-size")
+            .long("hash-max-size")
+            .value_name("BYTES")
+            .help("Files larger than this are not hashed with `--content-hash` and every write to them counts.")
+            .default_value("16777216"))
+        .arg(Arg::with_name("hash-cache-size")
+            .long("hash-cache-size")
+            .value_name("N")
+            .help("The maximum number of file hashes kept with `--content-hash`.")
+            .default_value("10000"))
+        .arg(Arg::with_name("restart")
+            .long("restart")
+            .help("Keeps the command running in the background and restarts it on every change. Useful for servers. \
+                   Same as `--on-busy cancel`.")
+            .conflicts_with("on-busy"))
+        .arg(Arg::with_name("on-busy")
+            .long("on-busy")
+            .value_name("POLICY")
+            .help("What to do with changes that arrive while the command is running.")
+            .possible_values(&["queue", "cancel", "ignore", "parallel"])
+            .default_value("queue"))
+        .arg(Arg::with_name("max-parallel")
+            .long("max-parallel")
+            .value_name("N")
+            .help("The maximum number of concurrent runs with `--on-busy parallel`.")
+            .default_value("2"))
+        .arg(Arg::with_name("debounce")
+            .long("debounce")
+            .value_name("DURATION")
+            .help("How long the changes need to stop before the command runs, e.g. `100ms` or `2s`.")
+            .default_value("100ms"))
+        .arg(Arg::with_name("throttle")
+            .long("throttle")
+            .value_name("DURATION")
+            .help("The minimum time between the starts of two runs.")
+            .default_value("0s"))
+        .arg(Arg::with_name("edge")
+            .long("edge")
+            .value_name("EDGE")
+            .help("Whether to run on the first change of a burst (leading) or after the burst ends (trailing).")
+            .possible_values(&["leading", "trailing"])
+            .default_value("trailing"))
+        .arg(Arg::with_name("per-file")
+            .long("per-file")
+            .help("Runs the command once for every changed file. \
+                   `{path}`, `{relpath}`, `{dir}`, `{stem}` and `{ext}` are replaced with the parts of the file path."))
+        .arg(Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .value_name("N")
+            .help("The maximum number of files processed at the same time with `--per-file`. \
+                   Defaults to the number of CPUs."))
+        .arg(Arg::with_name("rule")
+            .long("rule")
+            .value_name("GLOB=COMMAND")
+            .help("Runs COMMAND when a path matching GLOB changes. Can be repeated. \
+                   Each rule has its own runs. The positional command, if any, runs on every change.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("rules-file")
+            .long("rules-file")
+            .value_name("FILE")
+            .help("Reads rules from a JSON file: `{ \"rules\": [{ \"pattern\": \"*.rs\", \"command\": \"cargo test\" }] }`."))
+        .arg(Arg::with_name("args")
+            .value_name("COMMAND")
+            .help("The command to run. Everything after `--` is passed to the command as is. \
+                   A single argument is run as a shell script, multiple arguments reach the program exactly as typed.")
+            .multiple(true))
+}
+
+fn parse_matches(matches: &ArgMatches) -> Result<Options> {
+
+    let mut args = matches.values_of_os("args")
+        .map(|values| values.map(|x| x.to_os_string()).collect_vec())
+        .unwrap_or_default();
+
+    let mut roots = get_values(matches, "watch").into_iter()
+        .map(|path| WatchRootConfig { path, recursive: true })
+        .chain(get_values(matches, "watch-flat").into_iter().map(|path| WatchRootConfig { path, recursive: false }))
+        .collect_vec();
+
+    if roots.is_empty() {
+
+        if args.is_empty() {
+            return Err(CustomError::user_error("The path to watch is missing."));
+        }
+
+        let path = args.remove(0).into_string()
+            .map_err(|_| CustomError::user_error("The path to watch must be valid UTF-8."))?;
+
+        roots.push(WatchRootConfig { path, recursive: true });
+    }
+
+    // Once the path starts the trailing arguments, clap keeps the `--` that follows it.
+    if args.first().map(|x| x == "--").unwrap_or(false) {
+        args.remove(0);
+    }
+
+    let busy_policy = if matches.is_present("restart") {
+        BusyPolicy::Cancel
+    } else {
+        BusyPolicy::parse(
+            matches.value_of("on-busy").unwrap_or_default(),
+            matches.value_of("max-parallel").unwrap_or_default().parse()?
+        )?
+    };
+
+    let mut rules = get_values(matches, "rule")
+        .into_iter()
+        .map_result(|x| RuleConfig::parse(x))?
+        .collect_vec();
+
+    if let Some(rules_file) = matches.value_of("rules-file") {
+        rules.extend(read_rules_file(rules_file)?);
+    }
+
+    if args.is_empty() && rules.is_empty() {
+        return Err(CustomError::user_error("The command is missing."));
+    }
+
+    let include = get_values(matches, "include");
+    let exclude = get_values(matches, "exclude");
+
+    // The globs are compiled again later, but an invalid one is a usage error.
+    check_globs(&include)?;
+    check_globs(&exclude)?;
+    check_globs(&rules.iter().map(|x| x.pattern.clone()).collect_vec())?;
+
+    let content_hash = if matches.is_present("content-hash") {
+        Some(ContentHashConfig {
+            max_file_size: matches.value_of("hash-max-size").unwrap_or_default().parse()?,
+            max_entries: matches.value_of("hash-cache-size").unwrap_or_default().parse()?,
+        })
+    } else {
+        None
+    };
+
+    let shell = if matches.is_present("no-shell") {
+        None
+    } else {
+        Some(ShellConfig::new(
+            matches.value_of("shell").unwrap_or_default(),
+            matches.value_of("shell-preamble"),
+        ))
+    };
+
+    // `--poll` alone selects polling, an explicit `--backend` wins.
+    let backend_kind = if matches.occurrences_of("poll") > 0 && matches.occurrences_of("backend") == 0 {
+        BackendKind::Poll
+    } else {
+        BackendKind::parse(matches.value_of("backend").unwrap_or_default())?
+    };
+
+    let kill_grace = parse_duration(matches.value_of("kill-grace").unwrap_or_default())?;
+
+    let timeout = match matches.value_of("timeout") {
+        Some(value) => Some(Timeout {
+            duration: parse_duration(value)?,
+            grace: kill_grace,
+        }),
+        None => None,
+    };
+
+    let exit_after = if matches.is_present("once") {
+        Some(1)
+    } else {
+        match matches.value_of("exit-after") {
+            Some(value) => Some(value.parse()?),
+            None => None,
+        }
+    };
+
+    let idle_timeout = match matches.value_of("idle-timeout") {
+        Some(value) => Some(parse_duration(value)?),
+        None => None,
+    };
+
+    let initial_run = if matches.is_present("initial-run") {
+        InitialRun::Always
+    } else if matches.is_present("initial-run-if-changed") {
+        InitialRun::IfChanged
+    } else {
+        InitialRun::Never
+    };
+
+    let jobs = match matches.value_of("jobs") {
+        Some(value) => value.parse()?,
+        None => ::std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1),
+    };
+
+    Ok(Options {
+        roots,
+        command: if args.is_empty() { None } else { Some(CommandLine::from_args(args)?) },
+        backend: BackendConfig {
+            kind: backend_kind,
+            delay: parse_duration(matches.value_of("delay").unwrap_or_default())?,
+            poll_interval: parse_duration(matches.value_of("poll").unwrap_or_default())?,
+        },
+        shell,
+        forward_stdin: matches.is_present("stdin") && !matches.is_present("per-file"),
+        clear: matches.is_present("clear"),
+        timeout,
+        kill_grace,
+        exit_policy: ExitPolicyConfig {
+            exit_after,
+            idle_timeout,
+        },
+        initial_run,
+        state_dir: ::std::env::current_dir()?.join(matches.value_of("state-dir").unwrap_or_default()),
+        record: matches.is_present("record"),
+        rules,
+        filter: FilterConfig {
+            include,
+            exclude,
+            gitignore: matches.is_present("gitignore"),
+        },
+        event_kinds: EventKindFilter::parse(matches.value_of("on").unwrap_or_default())?,
+        content_hash,
+        busy_policy,
+        scheduler: SchedulerConfig {
+            debounce: parse_duration(matches.value_of("debounce").unwrap_or_default())?,
+            throttle: parse_duration(matches.value_of("throttle").unwrap_or_default())?,
+            edge: Edge::parse(matches.value_of("edge").unwrap_or_default())?,
+        },
+        per_file: matches.is_present("per-file"),
+        jobs,
+    })
+}
+
+fn parse_duration(value: &str) -> Result<Duration> {
+
+    humantime::parse_duration(value)
+        .map_err(|error| CustomError::user_error(&format!("Invalid duration `{}`: {}.", value, error)))
+}
+
+fn get_values(matches: &ArgMatches, name: &str) -> Vec<String> {
+
+    matches.values_of(name)
+        .map(|values| values.map(|x| x.to_string()).collect_vec())
+        .unwrap_or_default()
+}
@@ -0,0 +1,249 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use nix::sys::signal::Signal;
+
+use crate::global::prelude::*;
+use crate::global::bash_shell::{CommandResult, ShellConfig, SpawnOptions, Timeout, OutputFile, signal_group};
+use crate::command_line::{CommandLine, Placeholders};
+use crate::changes::{Change, ChangeKind};
+
+/// The result of running the command for a single file.
+#[derive(Debug)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub result: Result<CommandResult>,
+}
+
+/// The results of a per-file run.
+pub struct BatchSummary {
+    pub results: Vec<FileResult>,
+}
+
+impl BatchSummary {
+
+    pub fn passed_count(&self) -> usize {
+
+        self.results.iter()
+            .filter(|x| x.result.as_ref().map(|r| r.success).unwrap_or(false))
+            .count()
+    }
+
+    pub fn failed_count(&self) -> usize {
+
+        self.results.len() - self.passed_count()
+    }
+
+    /// Logs the passed/failed counts and the files that failed.
+    pub fn log(&self) -> Result {
+
+        log!("Per-file run finished: {} passed, {} failed.", self.passed_count(), self.failed_count());
+
+        for file_result in &self.results {
+
+            match &file_result.result {
+                Ok(result) if result.success => (),
+                Ok(result) => elog!("FAILED | {} ({})", file_result.path.get_as_string()?, result.exit_description()),
+                Err(err) => elog!("FAILED | {} ({:?})", file_result.path.get_as_string()?, err.kind),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A per-file run that executes in the background.
+pub struct Batch {
+    thread: JoinHandle<Result<BatchSummary>>,
+    stopped: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    /// The PIDs of the commands that are running right now.
+    active_pids: Arc<Mutex<Vec<u32>>>,
+}
+
+impl Batch {
+
+    /// Runs `command` once for every changed file that still exists, on at most `jobs` threads.
+    /// The output of all files goes to `output_file`, if set.
+    /// `on_exit` is called from a background thread when the batch finishes.
+    pub fn start<F>(
+        command: &CommandLine,
+        shell: &Option<ShellConfig>,
+        changes: &[Change],
+        jobs: usize,
+        timeout: Option<Timeout>,
+        output_file: Option<OutputFile>,
+        on_exit: F
+    ) -> Batch
+        where F: FnOnce() + Send + 'static {
+
+        let files = changes.iter()
+            .filter(|x| x.kind != ChangeKind::Remove && x.path.is_file())
+            .cloned()
+            .collect_vec();
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let active_pids = Arc::new(Mutex::new(Vec::new()));
+
+        let config = BatchConfig {
+            command: command.clone(),
+            shell: shell.clone(),
+            timeout,
+            output_file,
+            stopped: stopped.clone(),
+            active_pids: active_pids.clone(),
+        };
+
+        let thread_finished = finished.clone();
+
+        let thread = thread::spawn(move || {
+
+            let result = run_all(config, files, jobs);
+
+            thread_finished.store(true, Ordering::SeqCst);
+
+            on_exit();
+
+            result
+        });
+
+        Batch {
+            thread,
+            stopped,
+            finished,
+            active_pids,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Stops starting new files. The files that already started run to completion.
+    fn stop(&self) {
+
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops starting new files and sends the signal to the commands that are running.
+    pub fn signal(&self, signal: Signal) -> Result {
+
+        self.stop();
+
+        for pid in self.active_pids.lock()?.iter() {
+            signal_group(*pid, signal)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn wait(self) -> Result<BatchSummary> {
+
+        self.thread.join().replace_error(||
+            CustomError::from_message("The per-file thread failed for some reason."))?
+    }
+}
+
+/// What the worker threads of a per-file run share.
+#[derive(Clone)]
+struct BatchConfig {
+    command: CommandLine,
+    shell: Option<ShellConfig>,
+    timeout: Option<Timeout>,
+    output_file: Option<OutputFile>,
+    /// Set by `Batch::stop`.
+    stopped: Arc<AtomicBool>,
+    active_pids: Arc<Mutex<Vec<u32>>>,
+}
+
+fn run_all(config: BatchConfig, files: Vec<Change>, jobs: usize) -> Result<BatchSummary> {
+
+    let queue = Arc::new(Mutex::new(files));
+    let results = Arc::new(Mutex::new(Vec::new()));
+
+    let mut workers = Vec::new();
+
+    for _ in 0..jobs.max(1) {
+
+        let config = config.clone();
+        let queue = queue.clone();
+        let results = results.clone();
+
+        let worker: JoinHandle<Result> = thread::spawn(move || {
+
+            loop {
+                if config.stopped.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+
+                let file = match queue.lock()?.pop() {
+                    Some(x) => x,
+                    None => return Ok(()),
+                };
+
+                let spawn_result = config.command.spawn(&file_placeholders(&file.root, &file.path), &config.shell, SpawnOptions {
+                    timeout: config.timeout,
+                    output_file: config.output_file.clone(),
+                    ..Default::default()
+                });
+
+                let result = match spawn_result {
+                    Ok(child) => {
+                        let pid = child.pid();
+
+                        config.active_pids.lock()?.push(pid);
+
+                        // `Batch::signal` may have run between the spawn and the push.
+                        if config.stopped.load(Ordering::SeqCst) {
+                            child.terminate()?;
+                        }
+
+                        let result = child.wait();
+
+                        config.active_pids.lock()?.retain(|x| *x != pid);
+
+                        result
+                    },
+                    Err(err) => Err(err),
+                };
+
+                results.lock()?.push(FileResult { path: file.path, result });
+            }
+        });
+
+        workers.push(worker);
+    }
+
+    for worker in workers {
+        worker.join().replace_error(||
+            CustomError::from_message("A per-file worker thread failed for some reason."))??;
+    }
+
+    let results = ::std::mem::take(&mut *results.lock()?);
+
+    Ok(BatchSummary {
+        results: results.into_iter().order_by(|x| x.path.clone()).collect_vec(),
+    })
+}
+
+/// `{path}`, `{relpath}`, `{dir}`, `{stem}` and `{ext}` are the parts of `path`. `{root}` is the watch root of the file.
+fn file_placeholders(root: &Path, path: &Path) -> Placeholders {
+
+    let relative_path = path.strip_prefix(root).unwrap_or(path);
+
+    let part = |x: Option<&OsStr>| vec![x.map(|x| x.to_string_lossy().to_string()).unwrap_or_default()];
+
+    vec![
+        ("path", part(Some(path.as_os_str()))),
+        ("root", part(Some(root.as_os_str()))),
+        ("relpath", part(Some(relative_path.as_os_str()))),
+        ("dir", part(path.parent().map(|x| x.as_os_str()))),
+        ("stem", part(path.file_stem())),
+        ("ext", part(path.extension())),
+    ]
+}
@@ -0,0 +1,233 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::RecursiveMode;
+
+use crate::global::prelude::*;
+use crate::filters::PathFilter;
+use crate::backend::AnyWatcher;
+use crate::snapshot::Snapshot;
+
+/// How often the watch thread checks whether the roots were removed or created.
+pub static ROOT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A path given with `--watch` or `--watch-flat`.
+#[derive(Clone, Debug)]
+pub struct WatchRootConfig {
+    pub path: String,
+    /// `false` watches only the direct children of a directory.
+    pub recursive: bool,
+}
+
+/// The patterns that decide which changes under a root trigger a run.
+pub struct FilterConfig {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub gitignore: bool,
+}
+
+/// A watched directory or file and the filter for the changes under it.
+pub struct WatchRoot {
+    config: WatchRootConfig,
+    /// The path as given on the command line. Used in the log.
+    pub name: String,
+    /// The canonical path of the root.
+    pub path: PathBuf,
+    /// The directory that the patterns and relative paths are resolved against.
+    /// The root itself for a directory, and its parent directory for a file.
+    pub base: PathBuf,
+    pub recursive: bool,
+    pub filter: PathFilter,
+    /// What the root looked like after the last event, to work out what changed when the watcher loses events.
+    pub snapshot: Snapshot,
+    /// The device and inode of the root, to notice when it is replaced. `None` while the root does not exist.
+    identity: Option<(u64, u64)>,
+}
+
+/// What happened to a root since the last check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RootStatus {
+    Unchanged,
+    Removed,
+    /// The root was created, or removed and created again between two checks.
+    Created,
+}
+
+impl WatchRoot {
+
+    /// A root that does not exist yet is resolved against the current directory and treated as a directory until it is created.
+    pub fn new(config: &WatchRootConfig, filter_config: &FilterConfig) -> Result<WatchRoot> {
+
+        let path = match ::std::fs::canonicalize(&config.path) {
+            Ok(x) => x,
+            Err(_) => ::std::env::current_dir()?.join(&config.path),
+        };
+
+        let is_dir = path.is_dir();
+
+        let base = if is_dir {
+            path.clone()
+        } else {
+            path.parent().map(|x| x.to_path_buf()).unwrap_or_else(|| path.clone())
+        };
+
+        // The ignore files are only loaded for directories, so watching a single file does not walk its parent directory.
+        let filter = PathFilter::new(
+            &base,
+            &filter_config.include,
+            &filter_config.exclude,
+            filter_config.gitignore && is_dir,
+        )?;
+
+        let identity = get_identity(&path);
+
+        let snapshot = if identity.is_some() {
+            Snapshot::scan(&path, config.recursive, &filter)
+        } else {
+            Snapshot::empty()
+        };
+
+        Ok(WatchRoot {
+            config: config.clone(),
+            name: config.path.clone(),
+            snapshot,
+            identity,
+            path,
+            base,
+            recursive: config.recursive,
+            filter,
+        })
+    }
+
+    pub fn exists(&self) -> bool {
+
+        self.identity.is_some()
+    }
+
+    /// Compares the root with the file system and reloads it if it was created.
+    pub fn check(&mut self, filter_config: &FilterConfig) -> Result<RootStatus> {
+
+        let identity = match ::std::fs::canonicalize(&self.config.path) {
+            Ok(path) => get_identity(&path),
+            Err(_) => None,
+        };
+
+        if identity == self.identity {
+            return Ok(RootStatus::Unchanged);
+        }
+
+        match identity {
+            Some(_) => {
+                *self = WatchRoot::new(&self.config, filter_config)?;
+
+                Ok(RootStatus::Created)
+            },
+            None => {
+                self.identity = None;
+
+                Ok(RootStatus::Removed)
+            },
+        }
+    }
+
+    pub fn recursive_mode(&self) -> RecursiveMode {
+
+        if self.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        }
+    }
+}
+
+/// Checks all roots and re-arms the watcher for the ones that were created.
+/// Returns `true` if a root was created, because the changes inside it were not seen.
+pub fn check_roots(roots: &mut [WatchRoot], watcher: &mut AnyWatcher, filter_config: &FilterConfig) -> Result<bool> {
+
+    let mut created = false;
+
+    for root in roots.iter_mut() {
+
+        let old_path = root.path.clone();
+
+        match root.check(filter_config)? {
+            RootStatus::Unchanged => (),
+            RootStatus::Removed => {
+
+                // The watch of a removed directory may already be gone.
+                let _ = watcher.unwatch(&old_path);
+
+                log!("`{}` was removed. Waiting for it to be created again ...", root.name);
+            },
+            RootStatus::Created => {
+
+                let _ = watcher.unwatch(&old_path);
+
+                if let Err(error) = watcher.watch(&root.path, root.recursive_mode()) {
+
+                    // Probably removed again. The next check tries again.
+                    elog!("Failed to watch `{}`: {}", root.name, error.kind.to_string());
+
+                    root.identity = None;
+
+                    continue;
+                }
+
+                log!("`{}` was created. Watching `{}` ...", root.name, root.path.get_as_string()?);
+
+                created = true;
+            },
+        }
+    }
+
+    Ok(created)
+}
+
+/// Watches the directories of the ignore files that the watches of the roots don't cover, so that the ignore rules are reloaded when they change.
+/// The directories are watched instead of the files, so that creating a file is noticed too. A directory that does not exist yet is tried again on the next call.
+pub fn watch_ignore_files(roots: &[WatchRoot], watcher: &mut AnyWatcher, watched_directories: &mut Vec<PathBuf>) -> Result {
+
+    for root in roots.iter().filter(|x| x.exists()) {
+
+        for file_path in root.filter.extra_ignore_files() {
+
+            let directory = match file_path.parent() {
+                Some(x) => x,
+                None => continue,
+            };
+
+            if watched_directories.iter().any(|x| x == directory) || !directory.is_dir() {
+                continue;
+            }
+
+            let is_covered = roots.iter()
+                .filter(|x| x.exists())
+                .any(|x| directory == x.path || (x.recursive && directory.starts_with(&x.path)));
+
+            if !is_covered {
+
+                if let Err(error) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+                    elog!("The changes to `{}` will not be noticed. {}", file_path.get_as_string()?, error.kind.to_string());
+                }
+            }
+
+            watched_directories.push(directory.to_path_buf());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the root that `path` belongs to. If the roots are nested, the innermost one wins.
+pub fn find_root<'a>(roots: &'a mut [WatchRoot], path: &Path) -> Option<&'a mut WatchRoot> {
+
+    roots.iter_mut()
+        .filter(|x| path.starts_with(&x.path))
+        .max_by_key(|x| x.path.components().count())
+}
+
+fn get_identity(path: &Path) -> Option<(u64, u64)> {
+
+    ::std::fs::metadata(path).ok().map(|x| (x.dev(), x.ino()))
+}
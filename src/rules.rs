@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use globset::{Glob, GlobMatcher};
+use serde::{Serialize, Deserialize};
+
+use crate::global::prelude::*;
+use crate::command_line::CommandLine;
+use crate::changes::Change;
+
+/// A rule as written in the rules file or with `--rule`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleConfig {
+    pub pattern: String,
+    pub command: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RulesFile {
+    rules: Vec<RuleConfig>,
+}
+
+impl RuleConfig {
+
+    /// Parses a `GLOB=COMMAND` pair.
+    pub fn parse(value: &str) -> Result<RuleConfig> {
+
+        let separator_index = value.find('=')
+            .ok_or_else(|| CustomError::user_error(&format!("The rule `{}` is not in the `GLOB=COMMAND` format.", value)))?;
+
+        Ok(RuleConfig {
+            pattern: value[..separator_index].trim().to_string(),
+            command: value[separator_index + 1..].trim().to_string(),
+        })
+    }
+}
+
+/// Reads a JSON file in the format `{ "rules": [{ "pattern": "*.rs", "command": "cargo test" }] }`.
+pub fn read_rules_file(file_path: &str) -> Result<Vec<RuleConfig>> {
+    let json_content = ::std::fs::read_to_string(file_path)?;
+    let materialized: RulesFile = serde_json::from_str(&json_content)?;
+    Ok(materialized.rules)
+}
+
+/// Routes the changes under the watch roots to a command.
+pub struct Rule {
+    pub name: String,
+    pub command: CommandLine,
+    /// `None` matches every path.
+    matcher: Option<GlobMatcher>,
+}
+
+impl Rule {
+
+    /// The rule used when there are no rules and a single command handles every change.
+    pub fn catch_all(command: CommandLine) -> Rule {
+        Rule {
+            name: "default".to_string(),
+            command,
+            matcher: None,
+        }
+    }
+
+    pub fn new(config: &RuleConfig) -> Result<Rule> {
+
+        Ok(Rule {
+            name: config.pattern.clone(),
+            command: CommandLine::Script(config.command.clone()),
+            matcher: Some(Glob::new(&config.pattern)?.compile_matcher()),
+        })
+    }
+
+    /// Returns `true` if the rule handles the change. The pattern is matched against the path relative to the watch root.
+    pub fn is_match(&self, change: &Change) -> bool {
+
+        self.is_path_match(&change.root, &change.path)
+    }
+
+    /// Returns `true` if the rule handles the changes to `path`. `base` is the directory that the relative paths of the root are resolved against.
+    pub fn is_path_match(&self, base: &Path, path: &Path) -> bool {
+
+        match &self.matcher {
+            Some(matcher) => matcher.is_match(path.strip_prefix(base).unwrap_or(path)),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,588 @@
+use std::sync::mpsc::Sender;
+use std::path::{Path, PathBuf};
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nix::sys::signal::Signal;
+
+use crate::global::prelude::*;
+use crate::global::logger;
+use crate::global::logging::Color;
+use crate::global::bash_shell::{RunningCommand, ShellConfig, SpawnOptions, Timeout};
+use crate::scheduler::SchedulerEvent;
+use crate::changes::{Change, format_changed_files, change_placeholders};
+use crate::command_line::CommandLine;
+use crate::per_file::Batch;
+use crate::terminal::clear_screen;
+use crate::history::{RunRecorder, RunRecord};
+
+/// What to do with a change that arrives while the command is running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusyPolicy {
+    /// Run the command once more after the current run ends.
+    Queue,
+    /// Kill the current run and start over.
+    Cancel,
+    /// Drop the change.
+    Ignore,
+    /// Start another run as long as fewer than the given number of runs are active.
+    Parallel(usize),
+}
+
+impl BusyPolicy {
+
+    pub fn parse(value: &str, max_parallel: usize) -> Result<BusyPolicy> {
+
+        match value {
+            "queue" => Ok(BusyPolicy::Queue),
+            "cancel" => Ok(BusyPolicy::Cancel),
+            "ignore" => Ok(BusyPolicy::Ignore),
+            "parallel" => Ok(BusyPolicy::Parallel(max_parallel.max(1))),
+            _ => Err(CustomError::user_error(&format!("Unknown policy `{}`.", value))),
+        }
+    }
+}
+
+/// Reported to the watch thread, which decides when watch-run exits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunEvent {
+    Started,
+    /// The run exited with the given exit code. A run that was killed by a signal gets 128 + the signal number.
+    Finished(i32),
+}
+
+/// Called from the scheduler thread when a run starts or exits.
+pub type RunCallback = Box<dyn Fn(RunEvent) + Send>;
+
+/// Runs the command once per changed file instead of once per batch of changes.
+#[derive(Clone)]
+pub struct PerFileConfig {
+    /// The maximum number of files processed at the same time.
+    pub jobs: usize,
+}
+
+enum RunProcess {
+    Command(RunningCommand),
+    Batch(Batch),
+}
+
+/// An active run of the command.
+struct Run {
+    process: RunProcess,
+    /// Counts the runs of the runner, starting from 1.
+    number: u64,
+    started_at: Instant,
+    /// Identifies the rule in the log, e.g. `[*.rs] `.
+    log_prefix: String,
+    /// The JSON file with the changes that triggered the run. Deleted when the run exits.
+    changes_file_path: Option<PathBuf>,
+    /// Set if the output is saved with `--record`.
+    record: Option<RunRecord>,
+}
+
+impl Run {
+
+    fn has_exited(&mut self) -> Result<bool> {
+
+        match &mut self.process {
+            RunProcess::Command(child) => child.has_exited(),
+            RunProcess::Batch(batch) => Ok(batch.is_finished()),
+        }
+    }
+
+    /// Sends SIGTERM to the command, or to the commands of the per-file run, and stops starting new files.
+    fn stop(&self) -> Result {
+
+        match &self.process {
+            RunProcess::Command(child) => log!("{}Stopping the command (PID {}) ...", self.log_prefix, child.pid()),
+            RunProcess::Batch(_) => log!("{}Stopping the per-file run ...", self.log_prefix),
+        }
+
+        self.signal(Signal::SIGTERM)
+    }
+
+    /// Sends the signal to the process group of the command, or to the commands of the per-file run.
+    fn signal(&self, signal: Signal) -> Result {
+
+        match &self.process {
+            RunProcess::Command(child) => child.signal(signal),
+            RunProcess::Batch(batch) => batch.signal(signal),
+        }
+    }
+}
+
+pub struct RunnerConfig {
+    /// The name of the rule that the runner belongs to. Only set when there are multiple rules.
+    pub name: Option<String>,
+    /// The position of the rule. Keeps the files of the runs of different rules apart.
+    pub index: usize,
+    /// Where the changes files of the runs are created. See `ChangesDir`.
+    pub changes_dir: PathBuf,
+    pub command: CommandLine,
+    /// `None` runs the program directly, without a shell.
+    pub shell: Option<ShellConfig>,
+    pub forward_stdin: bool,
+    /// Clear the screen before every run.
+    pub clear: bool,
+    pub timeout: Option<Timeout>,
+    /// How long the runs have to exit after SIGTERM, when they are stopped or on shutdown, before they get SIGKILL.
+    pub kill_grace: Duration,
+    pub policy: BusyPolicy,
+    pub per_file: Option<PerFileConfig>,
+    pub on_run: Option<RunCallback>,
+    /// Set if the output of the runs is saved with `--record`.
+    pub recorder: Option<RunRecorder>,
+}
+
+/// Starts the command and tracks its runs according to the `BusyPolicy`.
+pub struct Runner {
+    command: CommandLine,
+    shell: Option<ShellConfig>,
+    forward_stdin: bool,
+    clear: bool,
+    timeout: Option<Timeout>,
+    kill_grace: Duration,
+    policy: BusyPolicy,
+    per_file: Option<PerFileConfig>,
+    index: usize,
+    changes_dir: PathBuf,
+    log_prefix: String,
+    running: Vec<Run>,
+    run_count: u64,
+    on_run: Option<RunCallback>,
+    recorder: Option<RunRecorder>,
+    /// Notified when a run exits.
+    sender: Sender<SchedulerEvent>,
+}
+
+impl Runner {
+
+    pub fn new(config: RunnerConfig, sender: Sender<SchedulerEvent>) -> Runner {
+        Runner {
+            command: config.command,
+            shell: config.shell,
+            forward_stdin: config.forward_stdin,
+            clear: config.clear,
+            timeout: config.timeout,
+            kill_grace: config.kill_grace,
+            policy: config.policy,
+            per_file: config.per_file,
+            index: config.index,
+            changes_dir: config.changes_dir,
+            log_prefix: config.name.map(|x| format!("[{}] ", x)).unwrap_or_default(),
+            running: Vec::new(),
+            run_count: 0,
+            on_run: config.on_run,
+            recorder: config.recorder,
+            sender,
+        }
+    }
+
+    /// Forwards the signal to all active runs and waits up to the kill grace period for them to exit.
+    /// The runs that are still active after that get SIGKILL.
+    pub fn shutdown(&mut self, signal: Signal) -> Result {
+
+        if self.running.is_empty() {
+            return Ok(());
+        }
+
+        log!("{}Sending {} to {} active run(s) ...", self.log_prefix, signal, self.running.len());
+
+        for run in &self.running {
+            run.signal(signal)?;
+        }
+
+        self.wait_or_kill()
+    }
+
+    /// Waits up to the kill grace period for the signalled runs to exit, sends SIGKILL to the rest and collects them all.
+    fn wait_or_kill(&mut self) -> Result {
+
+        let deadline = Instant::now() + self.kill_grace;
+
+        loop {
+            let mut all_exited = true;
+
+            for run in &mut self.running {
+                all_exited &= run.has_exited()?;
+            }
+
+            if all_exited || Instant::now() >= deadline {
+                break;
+            }
+
+            ::std::thread::sleep(Duration::from_millis(50));
+        }
+
+        for run in &mut self.running {
+
+            if !run.has_exited()? {
+
+                log!("{}Run #{} is still active after {}. Sending SIGKILL ...", run.log_prefix, run.number, humantime::format_duration(self.kill_grace));
+
+                run.signal(Signal::SIGKILL)?;
+            }
+        }
+
+        for run in self.running.drain(..) {
+            finish(run, &self.on_run)?;
+        }
+
+        Ok(())
+    }
+
+    /// Collects the finished runs and, if changes are pending, starts a new run when the policy allows it.
+    /// Returns `true` if the pending changes were consumed and `false` if they should stay pending.
+    pub fn handle(&mut self, pending_changes: Option<&[Change]>) -> Result<bool> {
+
+        self.reap()?;
+
+        let changes = match pending_changes {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+
+        match self.policy {
+            BusyPolicy::Queue => {
+                if !self.running.is_empty() {
+                    return Ok(false);
+                }
+            },
+            BusyPolicy::Cancel => {
+                self.stop_all()?;
+            },
+            BusyPolicy::Ignore => {
+                if !self.running.is_empty() {
+                    log!("{}Ignoring the change because the command is still running.", self.log_prefix);
+
+                    return Ok(true);
+                }
+            },
+            BusyPolicy::Parallel(max_parallel) => {
+                if self.running.len() >= max_parallel {
+                    return Ok(false);
+                }
+            },
+        }
+
+        self.start(changes)?;
+
+        Ok(true)
+    }
+
+    fn start(&mut self, changes: &[Change]) -> Result {
+
+        self.run_count += 1;
+
+        if self.clear {
+            clear_screen()?;
+        }
+
+        logger().log_colored(
+            &format!("{}=== Run #{} | {} ===", self.log_prefix, self.run_count, describe_triggers(changes)),
+            Color::Cyan
+        )?;
+
+        report(&self.on_run, RunEvent::Started);
+
+        let started_at = Instant::now();
+
+        // The run goes on without being recorded if its log file can't be created.
+        let record = match &self.recorder {
+            Some(recorder) => match recorder.start(self.run_count, changes) {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    elog!("{}Failed to record the run: {}", self.log_prefix, err.kind.to_string());
+
+                    None
+                },
+            },
+            None => None,
+        };
+
+        let output_file = record.as_ref().map(|x| x.output_file.clone());
+
+        if let Some(per_file) = &self.per_file {
+
+            let sender = self.sender.clone();
+
+            let batch = Batch::start(&self.command, &self.shell, changes, per_file.jobs, self.timeout, output_file, move || {
+                let _ = sender.send(SchedulerEvent::CommandExited);
+            });
+
+            log!("{}Started a per-file run with {} job(s).", self.log_prefix, per_file.jobs);
+
+            self.running.push(Run {
+                process: RunProcess::Batch(batch),
+                number: self.run_count,
+                started_at,
+                log_prefix: self.log_prefix.clone(),
+                changes_file_path: None,
+                record,
+            });
+
+            return Ok(());
+        }
+
+        let changes_file_path = self.changes_dir.join(format!("{}-{}.json", self.index, self.run_count));
+
+        if let Err(err) = write_changes_file(&changes_file_path, changes) {
+
+            elog!("{}Failed to write the changes file `{}`: {}", self.log_prefix, changes_file_path.get_as_string()?, err.kind.to_string());
+
+            return self.abort_start(&changes_file_path, record);
+        }
+
+        let sender = self.sender.clone();
+
+        let options = SpawnOptions {
+            env: vec![
+                ("WATCH_RUN_CHANGED_FILES".to_string(), format_changed_files(changes)),
+                ("WATCH_RUN_CHANGES_JSON".to_string(), changes_file_path.get_as_string()?),
+            ],
+            forward_stdin: self.forward_stdin,
+            timeout: self.timeout,
+            on_exit: Some(Box::new(move || {
+                let _ = sender.send(SchedulerEvent::CommandExited);
+            })),
+            output_file,
+        };
+
+        match self.command.spawn(&change_placeholders(changes), &self.shell, options) {
+            Ok(child) => {
+                log!("{}Started the command (PID {}).", self.log_prefix, child.pid());
+
+                self.running.push(Run {
+                    process: RunProcess::Command(child),
+                    number: self.run_count,
+                    started_at,
+                    log_prefix: self.log_prefix.clone(),
+                    changes_file_path: Some(changes_file_path),
+                    record,
+                });
+            },
+            Err(err) => {
+                elog!("{}Failed to start the command: {}", self.log_prefix, err.kind.to_string());
+
+                self.abort_start(&changes_file_path, record)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ends a run whose command could not be started. It counts as failed.
+    fn abort_start(&self, changes_file_path: &Path, record: Option<RunRecord>) -> Result {
+
+        remove_changes_file(changes_file_path)?;
+
+        finish_record(record, &self.log_prefix, 1, false)?;
+
+        report(&self.on_run, RunEvent::Finished(1));
+
+        Ok(())
+    }
+
+    /// Terminates all active runs and waits for them to exit. The runs that ignore SIGTERM get SIGKILL after the kill grace period.
+    pub fn stop_all(&mut self) -> Result {
+
+        for run in &self.running {
+            run.stop()?;
+        }
+
+        self.wait_or_kill()
+    }
+
+    /// Logs the exit status of the runs that exited on their own.
+    fn reap(&mut self) -> Result {
+
+        let mut still_running = Vec::new();
+
+        for mut run in self.running.drain(..) {
+
+            if run.has_exited()? {
+                finish(run, &self.on_run)?;
+            } else {
+                still_running.push(run);
+            }
+        }
+
+        self.running = still_running;
+
+        Ok(())
+    }
+}
+
+fn finish(run: Run, on_run: &Option<RunCallback>) -> Result {
+
+    let mut exit_code = 1;
+    let mut success = false;
+
+    match run.process {
+        RunProcess::Command(child) => {
+
+            let pid = child.pid();
+
+            match child.wait() {
+                Ok(result) => {
+                    exit_code = result.exit_code();
+                    success = result.success;
+
+                    log_end_banner(&run.log_prefix, run.number, run.started_at, result.success, &format!("{} | PID {}", result.exit_description(), pid))?;
+                },
+                Err(err) => elog!("{:#?}", err)
+            }
+        },
+        RunProcess::Batch(batch) => {
+
+            match batch.wait() {
+                Ok(summary) => {
+                    if summary.failed_count() == 0 {
+                        exit_code = 0;
+                        success = true;
+                    }
+
+                    summary.log()?;
+
+                    log_end_banner(&run.log_prefix, run.number, run.started_at, summary.failed_count() == 0, &format!(
+                        "{} passed, {} failed",
+                        summary.passed_count(),
+                        summary.failed_count()
+                    ))?;
+                },
+                Err(err) => elog!("{:#?}", err)
+            }
+        },
+    }
+
+    if let Some(changes_file_path) = &run.changes_file_path {
+        remove_changes_file(changes_file_path)?;
+    }
+
+    finish_record(run.record, &run.log_prefix, exit_code, success)?;
+
+    report(on_run, RunEvent::Finished(exit_code));
+
+    Ok(())
+}
+
+/// Adds the run to the history. A failure is only logged, because the run itself is done.
+fn finish_record(record: Option<RunRecord>, log_prefix: &str, exit_code: i32, success: bool) -> Result {
+
+    if let Some(record) = record {
+
+        if let Err(err) = record.finish(exit_code, success) {
+            elog!("{}Failed to add the run to the history: {}", log_prefix, err.kind.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+fn report(on_run: &Option<RunCallback>, event: RunEvent) {
+
+    if let Some(on_run) = on_run {
+        on_run(event);
+    }
+}
+
+fn log_end_banner(log_prefix: &str, number: u64, started_at: Instant, success: bool, details: &str) -> Result {
+
+    let elapsed = started_at.elapsed();
+    let elapsed = Duration::from_millis(elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()));
+
+    logger().log_colored(
+        &format!(
+            "{}=== Run #{} {} | {} | {} ===",
+            log_prefix,
+            number,
+            if success { "passed" } else { "failed" },
+            details,
+            humantime::format_duration(elapsed)
+        ),
+        if success { Color::Green } else { Color::Red }
+    )
+}
+
+/// Lists the first few changed paths, relative to their roots.
+fn describe_triggers(changes: &[Change]) -> String {
+
+    const MAX_PATHS: usize = 5;
+
+    if changes.is_empty() {
+        return "no changed paths".to_string();
+    }
+
+    let mut description = changes.iter()
+        .take(MAX_PATHS)
+        .map(|x| x.path.strip_prefix(&x.root).unwrap_or(&x.path).to_string_lossy().to_string())
+        .collect_vec()
+        .join(", ");
+
+    if changes.len() > MAX_PATHS {
+        description.push_str(&format!(" and {} more", changes.len() - MAX_PATHS));
+    }
+
+    description
+}
+
+/// A directory in `temp_dir()` for the changes files of the runs, that only the user can access. Removed when dropped.
+pub struct ChangesDir {
+    pub path: PathBuf,
+}
+
+impl ChangesDir {
+
+    pub fn create() -> Result<ChangesDir> {
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.subsec_nanos()).unwrap_or_default();
+
+        // The name is guessable, but `create` fails if the path already exists, so no one else can prepare it.
+        for attempt in 0..100 {
+
+            let path = ::std::env::temp_dir().join(format!("watch-run-{}-{}", ::std::process::id(), nanos.wrapping_add(attempt)));
+
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(ChangesDir { path }),
+                Err(ref error) if error.kind() == ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Err(CustomError::from_message("Failed to create a directory for the changes files."))
+    }
+}
+
+impl Drop for ChangesDir {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An existing file or link at the path is an error instead of being followed.
+fn write_changes_file(file_path: &Path, changes: &[Change]) -> Result {
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(file_path)?;
+
+    file.write_all(serde_json::to_string(changes)?.as_bytes())?;
+
+    Ok(())
+}
+
+/// A failure is only logged. The file is in a private directory that is removed on exit anyway.
+fn remove_changes_file(file_path: &Path) -> Result {
+
+    match ::std::fs::remove_file(file_path) {
+        Ok(()) => (),
+        Err(ref error) if error.kind() == ErrorKind::NotFound => (),
+        Err(error) => elog!("Failed to remove the changes file `{}`: {}", file_path.get_as_string()?, error),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,300 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::Signal;
+
+use crate::global::prelude::*;
+use crate::runner::Runner;
+use crate::changes::{Change, add_change};
+
+/// The messages that drive the scheduler.
+#[derive(Clone)]
+pub enum SchedulerEvent {
+    /// A watched path changed. Events that do not carry a path, like rescans, have no `Change`.
+    Change(Option<Change>),
+    /// A run of the command exited.
+    CommandExited,
+    /// Stop the active runs and drop the pending changes.
+    Kill,
+    /// Forward the signal to the active runs, wait for them to exit and return from `Scheduler::run`.
+    Shutdown(Signal),
+}
+
+/// When a burst of changes fires the command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    /// Fire on the first change of a burst. Changes that arrive during the debounce window
+    /// fire once more when the window closes.
+    Leading,
+    /// Fire once the changes stop for the debounce interval.
+    Trailing,
+}
+
+impl Edge {
+
+    pub fn parse(value: &str) -> Result<Edge> {
+
+        match value {
+            "leading" => Ok(Edge::Leading),
+            "trailing" => Ok(Edge::Trailing),
+            _ => Err(CustomError::user_error(&format!("Unknown edge `{}`.", value))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SchedulerConfig {
+    /// How long the changes need to stop before the debounce window closes.
+    pub debounce: Duration,
+    /// The minimum time between the starts of two runs.
+    pub throttle: Duration,
+    pub edge: Edge,
+}
+
+/// Decides when to start the command based on the incoming changes.
+pub struct Scheduler {
+    receiver: Receiver<SchedulerEvent>,
+    runner: Runner,
+    /// The changed paths since the last run.
+    changes: Vec<Change>,
+    timing: Timing,
+}
+
+impl Scheduler {
+
+    pub fn new(config: SchedulerConfig, receiver: Receiver<SchedulerEvent>, runner: Runner) -> Scheduler {
+        Scheduler {
+            receiver,
+            runner,
+            changes: Vec::new(),
+            timing: Timing::new(config),
+        }
+    }
+
+    /// Handles events until all senders are dropped or `Shutdown` arrives.
+    pub fn run(mut self) -> Result {
+
+        loop {
+            let event = match self.timing.next_deadline(Instant::now()) {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+
+                    match self.receiver.recv_timeout(timeout) {
+                        Ok(event) => Some(event),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                    }
+                },
+                None => match self.receiver.recv() {
+                    Ok(event) => Some(event),
+                    Err(_) => return Ok(()),
+                },
+            };
+
+            match event {
+                Some(SchedulerEvent::Change(change)) => {
+
+                    if let Some(change) = change {
+                        add_change(&mut self.changes, change);
+                    }
+
+                    self.timing.on_change(Instant::now());
+                },
+                Some(SchedulerEvent::Kill) => {
+                    self.changes.clear();
+                    self.timing.clear();
+
+                    self.runner.stop_all()?;
+                },
+                Some(SchedulerEvent::Shutdown(signal)) => return self.runner.shutdown(signal),
+                Some(SchedulerEvent::CommandExited) | None => (),
+            }
+
+            let now = Instant::now();
+
+            let pending_changes = if self.timing.is_due(now) {
+                Some(&self.changes[..])
+            } else {
+                None
+            };
+
+            if self.runner.handle(pending_changes)? {
+
+                self.changes.clear();
+                self.timing.on_run(now);
+            }
+        }
+    }
+}
+
+/// The timing decisions of the scheduler. The current time is passed in, so they don't depend on the clock.
+struct Timing {
+    config: SchedulerConfig,
+    /// There are changes that were not handed to the runner yet.
+    dirty: bool,
+    /// When the current debounce window closes.
+    quiet_at: Option<Instant>,
+    /// When the pending changes can be handed to the runner.
+    ready_at: Option<Instant>,
+    last_run_at: Option<Instant>,
+}
+
+impl Timing {
+
+    fn new(config: SchedulerConfig) -> Timing {
+        Timing {
+            config,
+            dirty: false,
+            quiet_at: None,
+            ready_at: None,
+            last_run_at: None,
+        }
+    }
+
+    fn on_change(&mut self, now: Instant) {
+
+        let window_is_open = self.quiet_at.map(|x| now < x).unwrap_or(false);
+
+        let quiet_at = now + self.config.debounce;
+
+        self.quiet_at = Some(quiet_at);
+        self.dirty = true;
+
+        self.ready_at = Some(match self.config.edge {
+            Edge::Leading if !window_is_open => now,
+            _ => quiet_at,
+        });
+    }
+
+    /// The pending changes were handed to the runner.
+    fn on_run(&mut self, now: Instant) {
+
+        self.dirty = false;
+        self.ready_at = None;
+        self.last_run_at = Some(now);
+    }
+
+    /// Drops the pending changes.
+    fn clear(&mut self) {
+
+        self.dirty = false;
+        self.ready_at = None;
+    }
+
+    fn earliest_run_at(&self) -> Option<Instant> {
+
+        let throttled_until = self.last_run_at.map(|x| x + self.config.throttle);
+
+        match (self.ready_at, throttled_until) {
+            (Some(ready_at), Some(throttled_until)) => Some(ready_at.max(throttled_until)),
+            (ready_at, _) => ready_at,
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+
+        self.dirty && self.earliest_run_at().map(|x| now >= x).unwrap_or(false)
+    }
+
+    /// The time at which the scheduler needs to wake up even if no events arrive.
+    /// Once the changes are due, the scheduler waits for a command to exit instead.
+    fn next_deadline(&self, now: Instant) -> Option<Instant> {
+
+        if !self.dirty {
+            return None;
+        }
+
+        self.earliest_run_at().filter(|x| *x > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn timing(edge: Edge, debounce_ms: u64, throttle_ms: u64) -> Timing {
+
+        Timing::new(SchedulerConfig {
+            debounce: Duration::from_millis(debounce_ms),
+            throttle: Duration::from_millis(throttle_ms),
+            edge,
+        })
+    }
+
+    fn ms(value: u64) -> Duration {
+
+        Duration::from_millis(value)
+    }
+
+    #[test]
+    fn leading_edge_fires_once_for_a_burst_and_again_when_the_window_closes() {
+
+        let start = Instant::now();
+        let mut timing = timing(Edge::Leading, 100, 0);
+
+        timing.on_change(start);
+
+        assert!(timing.is_due(start));
+
+        timing.on_run(start);
+
+        timing.on_change(start + ms(30));
+        timing.on_change(start + ms(60));
+
+        assert!(!timing.is_due(start + ms(60)));
+        assert!(!timing.is_due(start + ms(159)));
+        assert_eq!(timing.next_deadline(start + ms(60)), Some(start + ms(160)));
+        assert!(timing.is_due(start + ms(160)));
+    }
+
+    #[test]
+    fn trailing_edge_waits_until_the_changes_stop() {
+
+        let start = Instant::now();
+        let mut timing = timing(Edge::Trailing, 100, 0);
+
+        timing.on_change(start);
+        timing.on_change(start + ms(50));
+        timing.on_change(start + ms(120));
+
+        assert!(!timing.is_due(start + ms(150)));
+        assert!(!timing.is_due(start + ms(219)));
+        assert_eq!(timing.next_deadline(start + ms(150)), Some(start + ms(220)));
+        assert!(timing.is_due(start + ms(220)));
+
+        timing.on_run(start + ms(220));
+
+        assert!(!timing.is_due(start + ms(500)));
+        assert_eq!(timing.next_deadline(start + ms(500)), None);
+    }
+
+    #[test]
+    fn a_change_during_the_throttle_waits_for_it_to_pass() {
+
+        let start = Instant::now();
+        let mut timing = timing(Edge::Leading, 0, 1000);
+
+        timing.on_change(start);
+        timing.on_run(start);
+
+        timing.on_change(start + ms(200));
+
+        assert!(!timing.is_due(start + ms(200)));
+        assert_eq!(timing.next_deadline(start + ms(200)), Some(start + ms(1000)));
+        assert!(timing.is_due(start + ms(1000)));
+    }
+
+    #[test]
+    fn cleared_changes_do_not_fire() {
+
+        let start = Instant::now();
+        let mut timing = timing(Edge::Trailing, 100, 0);
+
+        timing.on_change(start);
+        timing.clear();
+
+        assert!(!timing.is_due(start + ms(200)));
+        assert_eq!(timing.next_deadline(start), None);
+    }
+}
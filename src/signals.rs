@@ -0,0 +1,47 @@
+use nix::sys::signal::Signal;
+use signal_hook::iterator::Signals;
+
+use crate::global::prelude::*;
+use crate::global::logger;
+use crate::global::bash_shell::kill_all;
+
+/// Handles SIGINT and SIGTERM instead of dying right away, and passes the first one to `on_signal` on a background thread.
+/// A second signal sends SIGKILL to all commands and exits, for when they don't stop or the user doesn't want to wait.
+/// The handlers are reset on exec, so the commands still get the default behaviour.
+pub fn wait_for_signals<F>(on_signal: F) -> Result
+    where F: FnOnce(Signal) + Send + 'static {
+
+    let signals = Signals::new([signal_hook::SIGINT, signal_hook::SIGTERM])?;
+
+    ::std::thread::spawn(move || -> Result {
+
+        let mut received = signals.forever().map(Signal::from_c_int);
+
+        if let Some(signal) = received.next() {
+            on_signal(signal?);
+        }
+
+        if let Some(signal) = received.next() {
+
+            let signal = signal?;
+
+            log!("Received {} while stopping. Killing the commands ...", signal);
+
+            kill_all()?;
+
+            logger().flush()?;
+
+            ::std::process::exit(signal_exit_code(signal));
+        }
+
+        Ok(())
+    });
+
+    Ok(())
+}
+
+/// The conventional exit code of a process that was stopped by a signal.
+pub fn signal_exit_code(signal: Signal) -> i32 {
+
+    128 + signal as i32
+}
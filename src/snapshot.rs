@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::hash::Hasher;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ignore::WalkBuilder;
+
+use crate::global::prelude::*;
+use crate::changes::{Change, ChangeKind};
+use crate::filters::PathFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    modified: Option<SystemTime>,
+    len: u64,
+    is_dir: bool,
+}
+
+impl Entry {
+
+    fn new(metadata: &Metadata) -> Entry {
+        Entry {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            is_dir: metadata.is_dir(),
+        }
+    }
+}
+
+/// The modification time and size of every path under a root.
+/// Used to work out what changed when the watcher loses events.
+pub struct Snapshot {
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Snapshot {
+
+    pub fn empty() -> Snapshot {
+        Snapshot {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Scans `path`, including its subdirectories if `recursive` is set. The symlinks are not followed.
+    /// `.git` and the paths excluded by `filter` are skipped with everything under them.
+    pub fn scan(path: &Path, recursive: bool, filter: &PathFilter) -> Snapshot {
+
+        let filter = filter.clone();
+
+        let walker = WalkBuilder::new(path)
+            .standard_filters(false)
+            .max_depth(if recursive { None } else { Some(1) })
+            .filter_entry(move |x| x.file_name() != ".git" && !filter.is_excluded(x.path()))
+            .build();
+
+        let entries = walker
+            .filter_map(|x| x.ok())
+            .filter_map(|x| x.metadata().ok().map(|metadata| (x.into_path(), Entry::new(&metadata))))
+            .collect();
+
+        Snapshot {
+            entries,
+        }
+    }
+
+    /// Updates a single path after the watcher reported a change to it.
+    pub fn update(&mut self, path: &Path) {
+
+        match ::std::fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                self.entries.insert(path.to_path_buf(), Entry::new(&metadata));
+            },
+            Err(_) => {
+                // A removed directory takes everything under it.
+                self.entries.retain(|x, _| !x.starts_with(path));
+            },
+        }
+    }
+
+    /// The paths of everything but the directories.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+
+        self.entries.iter()
+            .filter(|(_, entry)| !entry.is_dir)
+            .map(|(path, _)| path.as_path())
+    }
+
+    /// Hashes the files that pass `is_match` with their modification times and sizes, in a stable order.
+    /// The directories are left out, because creating a file in them changes their modification time too.
+    /// Only plain bytes and numbers are written, so the result does not depend on the `Hash` implementations of std.
+    pub fn hash_entries<H, F>(&self, hasher: &mut H, mut is_match: F)
+        where H: Hasher, F: FnMut(&Path) -> bool {
+
+        let mut paths = self.entries.iter()
+            .filter(|(path, entry)| !entry.is_dir && is_match(path))
+            .map(|(path, _)| path)
+            .collect_vec();
+        paths.sort();
+
+        for path in paths {
+
+            let entry = &self.entries[path];
+
+            let modified = entry.modified
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+
+            hasher.write(path.as_os_str().as_bytes());
+            hasher.write_u8(0);
+            hasher.write_u64(modified.as_secs());
+            hasher.write_u32(modified.subsec_nanos());
+            hasher.write_u64(entry.len);
+        }
+    }
+
+    /// Returns the changes between this snapshot and a newer one of the same root.
+    pub fn diff(&self, root: &Path, newer: &Snapshot) -> Vec<Change> {
+
+        let mut changes = Vec::new();
+
+        for (path, entry) in &newer.entries {
+
+            let kind = match self.entries.get(path) {
+                None => ChangeKind::Create,
+                Some(old_entry) if old_entry != entry && !entry.is_dir => ChangeKind::Write,
+                Some(_) => continue,
+            };
+
+            changes.push(Change { root: root.to_path_buf(), path: path.clone(), kind });
+        }
+
+        for path in self.entries.keys() {
+
+            if !newer.entries.contains_key(path) {
+                changes.push(Change { root: root.to_path_buf(), path: path.clone(), kind: ChangeKind::Remove });
+            }
+        }
+
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        changes
+    }
+}
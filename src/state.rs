@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::global::prelude::*;
+use crate::roots::WatchRoot;
+use crate::rules::Rule;
+use crate::runner::RunEvent;
+
+static STATE_FILE_NAME: &str = "state.json";
+
+/// Whether the commands run once when watch-run starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InitialRun {
+    Never,
+    Always,
+    /// Only if the watched files or the arguments changed since the last successful run of the rule.
+    IfChanged,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    /// The fingerprint of the watched files when the last successful run of a rule started, by the position of the rule.
+    last_success: HashMap<usize, u64>,
+}
+
+/// Remembers the watched files of the last successful run of every rule in the state directory.
+pub struct StateStore {
+    state_dir: PathBuf,
+    state: State,
+    /// The fingerprint when the last run of a rule started. Saved if the run succeeds.
+    run_fingerprints: HashMap<usize, u64>,
+    /// Whether a file counts for the fingerprint of a rule, by the positions of the rule and the root.
+    /// Matching a path stats it, so the results are kept until the filters change.
+    matches: HashMap<(usize, usize), HashMap<PathBuf, bool>>,
+}
+
+impl StateStore {
+
+    /// A missing or unreadable state file counts as no successful run.
+    pub fn load(state_dir: &Path) -> Result<StateStore> {
+
+        let file_path = state_dir.join(STATE_FILE_NAME);
+
+        let state = if file_path.exists() {
+            serde_json::from_str(&::std::fs::read_to_string(&file_path)?).unwrap_or_default()
+        } else {
+            State::default()
+        };
+
+        Ok(StateStore {
+            state_dir: state_dir.to_path_buf(),
+            state,
+            run_fingerprints: HashMap::new(),
+            matches: HashMap::new(),
+        })
+    }
+
+    /// Returns `true` if the files of the rule or the arguments of watch-run changed since the last successful run of the rule.
+    pub fn is_changed(&mut self, rule_index: usize, rule: &Rule, roots: &[WatchRoot]) -> bool {
+
+        let fingerprint = self.fingerprint(rule_index, rule, roots);
+
+        self.state.last_success.get(&rule_index) != Some(&fingerprint)
+    }
+
+    pub fn on_run(&mut self, rule_index: usize, rule: &Rule, event: RunEvent, roots: &[WatchRoot]) -> Result {
+
+        match event {
+            RunEvent::Started => {
+                let fingerprint = self.fingerprint(rule_index, rule, roots);
+
+                self.run_fingerprints.insert(rule_index, fingerprint);
+            },
+            RunEvent::Finished(0) => {
+                if let Some(fingerprint) = self.run_fingerprints.get(&rule_index) {
+
+                    self.state.last_success.insert(rule_index, *fingerprint);
+
+                    ::std::fs::create_dir_all(&self.state_dir)?;
+                    ::std::fs::write(self.state_dir.join(STATE_FILE_NAME), serde_json::to_string(&self.state)?)?;
+                }
+            },
+            RunEvent::Finished(_) => (),
+        }
+
+        Ok(())
+    }
+
+    /// Forgets which files match the rules. Should be called when the filters of the roots change.
+    pub fn clear_matches(&mut self) {
+
+        self.matches.clear();
+    }
+
+    /// Hashes the arguments of watch-run and the modification times and sizes of the files that pass the filters of their root and the pattern of the rule.
+    fn fingerprint(&mut self, rule_index: usize, rule: &Rule, roots: &[WatchRoot]) -> u64 {
+
+        let mut hasher = FnvHasher::new();
+
+        for arg in ::std::env::args_os() {
+            hasher.write(arg.as_bytes());
+            hasher.write_u8(0);
+        }
+
+        let state_dir = &self.state_dir;
+
+        for (root_index, root) in roots.iter().enumerate() {
+
+            let matches = self.matches.entry((rule_index, root_index)).or_default();
+
+            root.snapshot.hash_entries(&mut hasher, |path| {
+
+                if let Some(is_match) = matches.get(path) {
+                    return *is_match;
+                }
+
+                let is_match = rule.is_path_match(&root.base, path) && !path.starts_with(state_dir) && root.filter.is_match(path);
+
+                matches.insert(path.to_path_buf(), is_match);
+
+                is_match
+            });
+        }
+
+        hasher.finish()
+    }
+}
+
+/// 64-bit FNV-1a. The fingerprints are saved, so unlike `DefaultHasher` the hash must not change between builds of watch-run.
+struct FnvHasher {
+    hash: u64,
+}
+
+impl FnvHasher {
+
+    fn new() -> FnvHasher {
+        FnvHasher {
+            hash: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+}
+
+impl Hasher for FnvHasher {
+
+    fn finish(&self) -> u64 {
+
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+
+        for byte in bytes {
+            self.hash ^= u64::from(*byte);
+            self.hash = self.hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
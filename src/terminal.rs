@@ -0,0 +1,98 @@
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use nix::sys::termios::{self, LocalFlags, SetArg, Termios};
+use nix::unistd::isatty;
+
+use crate::global::prelude::*;
+
+/// A command typed in the terminal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyCommand {
+    /// `r` or Enter: run all commands now.
+    Rerun,
+    /// `p`: stop or start reacting to changes.
+    Pause,
+    /// `c`: clear the screen.
+    Clear,
+    /// `k`: kill the running commands.
+    Kill,
+    /// `q`: stop the commands and exit.
+    Quit,
+}
+
+impl KeyCommand {
+
+    fn from_key(key: u8) -> Option<KeyCommand> {
+
+        match key {
+            b'r' | b'\n' | b'\r' => Some(KeyCommand::Rerun),
+            b'p' => Some(KeyCommand::Pause),
+            b'c' => Some(KeyCommand::Clear),
+            b'k' => Some(KeyCommand::Kill),
+            b'q' => Some(KeyCommand::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// Turns off line buffering and echo on the terminal until dropped, so single key presses are read right away.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(::std::io::stdin().as_raw_fd(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Reads key presses from stdin on a background thread and passes them to `on_command`
+/// until it returns `false` or stdin is closed.
+/// Returns `None` without starting anything if stdin is not a terminal.
+pub fn start_keyboard<F>(on_command: F) -> Result<Option<RawMode>>
+    where F: Fn(KeyCommand) -> bool + Send + 'static {
+
+    let fd = ::std::io::stdin().as_raw_fd();
+
+    if !isatty(fd)? {
+        return Ok(None);
+    }
+
+    let original = termios::tcgetattr(fd)?;
+
+    let mut raw = original.clone();
+    raw.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO);
+
+    termios::tcsetattr(fd, SetArg::TCSANOW, &raw)?;
+
+    ::std::thread::spawn(move || {
+
+        let mut buffer = [0; 1];
+
+        while let Ok(1) = ::std::io::stdin().read(&mut buffer) {
+
+            if let Some(command) = KeyCommand::from_key(buffer[0]) {
+
+                if !on_command(command) {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(Some(RawMode {
+        original,
+    }))
+}
+
+/// Clears the screen and the scrollback of the terminal.
+pub fn clear_screen() -> Result {
+
+    let mut stdout = ::std::io::stdout();
+
+    stdout.write_all(b"\x1b[2J\x1b[3J\x1b[H")?;
+    stdout.flush()?;
+
+    Ok(())
+}